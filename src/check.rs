@@ -0,0 +1,49 @@
+use crate::config::Config;
+use crate::discover::{discover, is_exported};
+use anyhow::Result;
+use git2::Repository;
+use std::path::Path;
+
+/// Prints the repositories found under `config.dir` along with whether their
+/// default revision resolves, then the directories holding no repository and
+/// the configured repositories that were not found, and returns the number
+/// of problems seen
+pub fn check(config: &Config) -> Result<usize> {
+    let mut problems = 0;
+    println!("dir: {}", config.dir);
+    for addr in &config.listen {
+        println!("listen: {}", addr);
+    }
+
+    let discovery = discover(&config.dir, config.scan_depth);
+    for name in &discovery.repos {
+        let repo = Repository::open(Path::new(&config.dir).join(name))?;
+        let rev = config.default_rev(name);
+        let hidden = match config.repo(name) {
+            _ if !is_exported(config, name, &repo) => " (not exported)",
+            Some(r) if r.hidden => " (hidden)",
+            _ => "",
+        };
+        match repo.revparse_single(rev).map(|_| ()) {
+            Ok(()) => println!("ok     {} [{}]{}", name, rev, hidden),
+            Err(e) => {
+                println!("error  {} [{}]: {}", name, rev, e.message());
+                problems += 1;
+            }
+        }
+    }
+    for (name, e) in &discovery.errors {
+        println!("error  {}: {}", name, e.message());
+        problems += 1;
+    }
+
+    let mut configured = config.repos.keys().collect::<Vec<_>>();
+    configured.sort();
+    for name in configured {
+        if !discovery.repos.contains(name) {
+            println!("error  repos.{}: no such repository", name);
+            problems += 1;
+        }
+    }
+    Ok(problems)
+}
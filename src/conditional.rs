@@ -0,0 +1,413 @@
+use crate::data::{find_commit, find_path, open_repo, resolve_commit};
+use crate::handlers::snapshot::Format;
+use crate::handlers::{api, feed, log};
+use crate::util::{print_time_http, urldecode};
+use crate::worker::blocking;
+use anyhow::{bail, Result};
+use axum::{
+    extract::{
+        rejection::RawPathParamsRejection, MatchedPath, Query, RawPathParams,
+        Request,
+    },
+    http::{header, HeaderMap, HeaderValue, Method, StatusCode, Uri},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use chrono::DateTime;
+use git2::{ObjectType, Oid, Time};
+
+/// Lets clients cache pages whose URL names an object id for good
+const CACHE_IMMUTABLE: &str = "public, max-age=31536000, immutable";
+
+/// Makes clients check with the server before using a page named by a ref,
+/// which is cheap thanks to the ETag
+const CACHE_REVALIDATE: &str = "no-cache";
+
+/// Routes whose pages are mostly made from the commit they name, and so are
+/// marked immutable when it is named by its full id. The commit page also
+/// shows the description, owner and clone URLs of the repository, which a
+/// client may therefore keep seeing as they were.
+const IMMUTABLE_ROUTES: [&str; 4] = [
+    "/:repo/commit/:hash",
+    "/:repo/raw/:rev/*path",
+    "/:repo/snapshot/:file",
+    "/repos/:repo/commit/:hash",
+];
+
+/// Route of the history of a path, which may no longer exist in the commit
+const LOG_PATH_ROUTE: &str = "/:repo/log/:rev/*path";
+
+/// Routes showing a file, whose path must lead to a blob rather than a tree
+const BLOB_ROUTES: [&str; 2] =
+    ["/:repo/blame/:rev/*path", "/:repo/raw/:rev/*path"];
+
+/// Routes of the log pages, taking a [log::LogQuery]
+const LOG_ROUTES: [&str; 4] = [
+    "/:repo",
+    "/:repo/log",
+    "/:repo/log/:rev",
+    "/:repo/log/:rev/*path",
+];
+
+/// Routes of the API log, taking an [api::LogQuery], below its prefix
+const API_LOG_ROUTES: [&str; 3] = [
+    "/repos/:repo/log",
+    "/repos/:repo/log/:rev",
+    "/repos/:repo/log/:rev/*path",
+];
+
+/// Route of the commit feed, taking a [feed::FeedQuery]
+const FEED_ROUTE: &str = "/:repo/atom.xml";
+
+/// What a page is made from, as far as caching is concerned
+enum Target {
+    /// The commit a revision resolves to, or that `hash` names, and the path
+    /// in its tree the page shows if it must exist
+    Rev {
+        rev: String,
+        hash: bool,
+        path: Option<ShownPath>,
+    },
+    /// Every ref of the repository
+    Refs,
+}
+
+/// A path a page shows, and whether it must be a file
+struct ShownPath {
+    path: String,
+    blob: bool,
+}
+
+/// Validators of a page, telling whether a cached copy is still good
+struct Validators {
+    etag: String,
+    last_modified: Option<Time>,
+    immutable: bool,
+}
+
+/// Adds an `ETag` and a `Last-Modified` header, derived from the commit the
+/// page shows or from all the refs of the repository, to the pages of a
+/// repository and answers 304 to `If-None-Match` and `If-Modified-Since`
+/// requests the cached copy of which is still good. Only refs are resolved
+/// to tell, the page itself is not rendered, so 304 is only answered when
+/// the handler would accept the parameters; any other request is left for
+/// it to answer. Pages of [IMMUTABLE_ROUTES] whose URL names a commit by its
+/// full id are marked immutable.
+pub async fn conditional(
+    params: Result<RawPathParams, RawPathParamsRejection>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Ok(params) = params else {
+        return next.run(request).await;
+    };
+    if !matches!(*request.method(), Method::GET | Method::HEAD) {
+        return next.run(request).await;
+    }
+    let param = |name: &str| {
+        params
+            .iter()
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| value.to_string())
+    };
+    let Some(repo) = param("repo") else {
+        return next.run(request).await;
+    };
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or("", |route| route.as_str());
+    let snapshot_rev = match param("file") {
+        Some(file) => match Format::split(&file) {
+            Some((rev, _)) => Some(rev.to_string()),
+            None => return next.run(request).await,
+        },
+        None => None,
+    };
+    if !accepts_query(route, request.uri()) {
+        return next.run(request).await;
+    }
+    let hash = param("hash");
+    let is_hash = hash.is_some();
+    let rev = hash
+        .or(param("rev"))
+        .or(snapshot_rev)
+        .or(query_ref(request.uri().query()));
+    let immutable = IMMUTABLE_ROUTES.iter().any(|r| route.ends_with(r))
+        && rev.as_deref().and_then(full_oid).is_some();
+    let path = param("path")
+        .filter(|_| !route.ends_with(LOG_PATH_ROUTE))
+        .map(|path| ShownPath {
+            path,
+            blob: BLOB_ROUTES.iter().any(|r| route.ends_with(r)),
+        });
+    let target = match rev {
+        Some(rev) => Target::Rev {
+            rev,
+            hash: is_hash,
+            path,
+        },
+        None => Target::Refs,
+    };
+
+    let validators = blocking(move || validators(&repo, target, immutable));
+    let Ok(validators) = validators.await else {
+        // The handler tells what is wrong better
+        return next.run(request).await;
+    };
+    if is_fresh(request.headers(), &validators) {
+        return not_modified(&validators);
+    }
+    let mut response = next.run(request).await;
+    if response.status() == StatusCode::OK {
+        set_headers(response.headers_mut(), &validators);
+    }
+    response
+}
+
+fn validators(
+    repo: &str,
+    target: Target,
+    immutable: bool,
+) -> Result<Validators> {
+    let repo = open_repo(repo)?;
+    match target {
+        Target::Rev { rev, hash, path } => {
+            let commit = match hash {
+                true => find_commit(&repo, &rev)?,
+                false => resolve_commit(&repo, &rev)?,
+            };
+            if let Some(ShownPath { path, blob }) = path {
+                let obj = find_path(&repo, &commit, path.trim_matches('/'))?;
+                if blob && obj.kind() != Some(ObjectType::Blob) {
+                    bail!("Not a file: {}", path);
+                }
+            }
+            let time = commit.committer().when();
+            Ok(Validators {
+                etag: etag(commit.id()),
+                last_modified: Some(time),
+                immutable,
+            })
+        }
+        Target::Refs => {
+            // The refs are summed up by the id of a listing of them
+            let mut listing = String::new();
+            let mut last_modified: Option<Time> = None;
+            let head = repo.head().ok().and_then(|h| h.resolve().ok());
+            let refs = repo.references()?.filter_map(|rf| rf.ok());
+            for rf in head.into_iter().chain(refs) {
+                let (Some(name), Some(oid)) = (rf.name(), rf.target()) else {
+                    continue;
+                };
+                listing.push_str(&format!("{} {}\n", oid, name));
+                if let Ok(commit) = rf.peel_to_commit() {
+                    let time = commit.committer().when();
+                    if last_modified
+                        .is_none_or(|t| t.seconds() < time.seconds())
+                    {
+                        last_modified = Some(time);
+                    }
+                }
+            }
+            let oid = Oid::hash_object(ObjectType::Blob, listing.as_bytes())?;
+            Ok(Validators {
+                etag: etag(oid),
+                last_modified,
+                immutable: false,
+            })
+        }
+    }
+}
+
+fn etag(oid: Oid) -> String {
+    format!("\"{}\"", oid)
+}
+
+/// Returns whether the handler of `route` accepts the query of `uri`, as
+/// the ones taking none ignore it
+fn accepts_query(route: &str, uri: &Uri) -> bool {
+    if LOG_ROUTES.contains(&route) {
+        Query::<log::LogQuery>::try_from_uri(uri).is_ok()
+    } else if API_LOG_ROUTES.iter().any(|r| route.ends_with(r)) {
+        Query::<api::LogQuery>::try_from_uri(uri).is_ok()
+    } else if route == FEED_ROUTE {
+        Query::<feed::FeedQuery>::try_from_uri(uri).is_ok()
+    } else {
+        true
+    }
+}
+
+/// Returns the id `rev` spells out in full, if it does
+fn full_oid(rev: &str) -> Option<Oid> {
+    let is_hex = rev.bytes().all(|b| b.is_ascii_hexdigit());
+    match rev.len() {
+        40 | 64 if is_hex => Oid::from_str(rev).ok(),
+        _ => None,
+    }
+}
+
+/// Returns the `ref` query parameter, by which feeds take their revision
+fn query_ref(query: Option<&str>) -> Option<String> {
+    query?
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("ref="))
+        .map(urldecode)
+}
+
+/// Returns whether `If-None-Match` lists `etag`, if it is present
+fn if_none_match(headers: &HeaderMap, etag: &str) -> Option<bool> {
+    let value = headers.get(header::IF_NONE_MATCH)?.to_str().ok()?;
+    Some(value.split(',').map(|tag| tag.trim()).any(|tag| {
+        // Weak comparison, as required for If-None-Match
+        tag == "*" || tag.trim_start_matches("W/") == etag
+    }))
+}
+
+/// Returns whether the copy the client holds is still good, as told by
+/// `If-None-Match` or, lacking it, `If-Modified-Since`
+fn is_fresh(headers: &HeaderMap, validators: &Validators) -> bool {
+    if let Some(matches) = if_none_match(headers, &validators.etag) {
+        return matches;
+    }
+    let since = headers
+        .get(header::IF_MODIFIED_SINCE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| DateTime::parse_from_rfc2822(v).ok());
+    match (since, validators.last_modified) {
+        (Some(since), Some(time)) => time.seconds() <= since.timestamp(),
+        _ => false,
+    }
+}
+
+fn not_modified(validators: &Validators) -> Response {
+    let mut response = StatusCode::NOT_MODIFIED.into_response();
+    set_headers(response.headers_mut(), validators);
+    response
+}
+
+fn set_headers(headers: &mut HeaderMap, validators: &Validators) {
+    if let Ok(etag) = HeaderValue::from_str(&validators.etag) {
+        headers.insert(header::ETAG, etag);
+    }
+    let cache_control = match validators.immutable {
+        true => CACHE_IMMUTABLE,
+        false => CACHE_REVALIDATE,
+    };
+    headers.insert(
+        header::CACHE_CONTROL,
+        HeaderValue::from_static(cache_control),
+    );
+    if let Some(time) = validators.last_modified {
+        let mut date = String::new();
+        if print_time_http(&mut date, time).is_ok() {
+            if let Ok(date) = HeaderValue::from_str(&date) {
+                headers.insert(header::LAST_MODIFIED, date);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OID: &str = "04d6345b717172f8bdf6db9bb956c3e0d31bf973";
+
+    fn headers(pairs: &[(header::HeaderName, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(name, HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    /// Validators of a page last modified on Sat, 17 Oct 2026 03:05:01 GMT
+    fn validators() -> Validators {
+        Validators {
+            etag: format!("\"{}\"", OID),
+            last_modified: Some(Time::new(1792206301, 0)),
+            immutable: false,
+        }
+    }
+
+    #[test]
+    fn full_oid_takes_full_ids_only() {
+        assert_eq!(full_oid(OID), Oid::from_str(OID).ok());
+        for rev in ["04d6345", "HEAD", "master", "", &OID[..39]] {
+            assert_eq!(full_oid(rev), None, "{:?}", rev);
+        }
+        let not_hex = format!("{}g", &OID[..39]);
+        assert_eq!(full_oid(&not_hex), None);
+    }
+
+    #[test]
+    fn query_ref_finds_the_ref_parameter() {
+        assert_eq!(query_ref(None), None);
+        assert_eq!(query_ref(Some("")), None);
+        assert_eq!(query_ref(Some("ref=v1.0")), Some("v1.0".into()));
+        assert_eq!(
+            query_ref(Some("a=b&ref=feature%2Fx")),
+            Some("feature/x".into())
+        );
+        assert_eq!(query_ref(Some("reference=x")), None);
+    }
+
+    #[test]
+    fn if_none_match_compares_weakly() {
+        let etag = format!("\"{}\"", OID);
+        assert_eq!(if_none_match(&HeaderMap::new(), &etag), None);
+        for value in [
+            etag.clone(),
+            format!("W/{}", etag),
+            format!("\"other\", {}", etag),
+            "*".to_string(),
+        ] {
+            let h = headers(&[(header::IF_NONE_MATCH, &value)]);
+            assert_eq!(if_none_match(&h, &etag), Some(true), "{}", value);
+        }
+        for value in ["\"other\"", OID] {
+            let h = headers(&[(header::IF_NONE_MATCH, value)]);
+            assert_eq!(if_none_match(&h, &etag), Some(false), "{}", value);
+        }
+    }
+
+    #[test]
+    fn is_fresh_prefers_if_none_match() {
+        let v = validators();
+        assert!(!is_fresh(&HeaderMap::new(), &v));
+        let h = headers(&[
+            (header::IF_NONE_MATCH, "\"other\""),
+            (header::IF_MODIFIED_SINCE, "Sat, 17 Oct 2026 03:05:01 GMT"),
+        ]);
+        assert!(!is_fresh(&h, &v));
+        let h = headers(&[
+            (header::IF_NONE_MATCH, &v.etag),
+            (header::IF_MODIFIED_SINCE, "Mon, 01 Jan 2001 00:00:00 GMT"),
+        ]);
+        assert!(is_fresh(&h, &v));
+    }
+
+    #[test]
+    fn is_fresh_compares_dates() {
+        let v = validators();
+        for (since, fresh) in [
+            ("Sat, 17 Oct 2026 03:05:01 GMT", true),
+            ("Sun, 18 Oct 2026 00:00:00 GMT", true),
+            ("Sat, 17 Oct 2026 03:05:00 GMT", false),
+            ("yesterday", false),
+        ] {
+            let h = headers(&[(header::IF_MODIFIED_SINCE, since)]);
+            assert_eq!(is_fresh(&h, &v), fresh, "{}", since);
+        }
+        let v = Validators {
+            last_modified: None,
+            ..validators()
+        };
+        let h = headers(&[(
+            header::IF_MODIFIED_SINCE,
+            "Sat, 17 Oct 2026 03:05:01 GMT",
+        )]);
+        assert!(!is_fresh(&h, &v));
+    }
+}
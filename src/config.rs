@@ -1,19 +1,397 @@
+use anyhow::{anyhow, bail, Context, Result};
+use serde::Deserialize;
+use std::collections::HashMap;
 use std::env;
+use std::fmt;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::OnceLock;
+use std::time::Duration;
 
 const STD_PORT: u16 = 3000;
+const STD_LOG_PAGE_SIZE: usize = 100;
+const STD_LOG_MAX_PAGES: usize = 10;
+const STD_LOG_DIFFSTAT: bool = true;
+const STD_FEED_SIZE: usize = 100;
+const STD_SITE_TITLE: &str = "Repositories";
+const STD_CLONE_URL: &str = "git://{repo}.git";
+const STD_SOCKET_MODE: u32 = 0o660;
+const STD_SCAN_DEPTH: usize = 1;
+const STD_REQUEST_TIMEOUT: u64 = 30;
+const STD_REPO_POOL_SIZE: usize = 32;
+const STD_DIFFSTAT_CACHE_SIZE: usize = 10000;
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
 
 pub struct Config {
     pub dir: String,
-    pub port: u16,
+    /// How many levels of directories below `dir` are searched for
+    /// repositories, 1 being only its direct children
+    pub scan_depth: usize,
+    /// File listing the only repositories to expose, one path relative to
+    /// `dir` per line, as in the `projects.list` of gitolite and gitweb
+    pub project_list: Option<String>,
+    /// Whether only repositories containing a `git-daemon-export-ok` file
+    /// are exposed, as with `git daemon`
+    pub export_ok: bool,
+    /// Addresses to accept connections on, from `listen` or else from `bind`
+    /// and `port`
+    pub listen: Vec<ListenAddr>,
+    /// Permissions given to the Unix domain sockets ritz creates
+    pub socket_mode: u32,
+    /// How many requests may work on repositories at once, the others
+    /// waiting for their turn
+    pub git_workers: usize,
+    /// How long a request may wait for and work on a repository before it is
+    /// given up on
+    pub request_timeout: Duration,
+    /// How many opened repositories are kept around for later requests
+    pub repo_pool_size: usize,
+    /// How many commit diffstats are kept in memory, 0 disabling the cache
+    pub diffstat_cache_size: usize,
+    /// Directory holding the commit index of every repository, which is
+    /// only kept if set
+    pub cache_dir: Option<String>,
+    pub log_page_size: usize,
+    /// Most commits a page of the log may hold, whatever `limit` asks for,
+    /// `log_page_size` times ten unless set
+    pub log_max_page_size: usize,
+    /// Whether the log shows the files, additions and deletions of every
+    /// commit, which takes a diff each
+    pub log_diffstat: bool,
+    pub feed_size: usize,
+    pub site_title: String,
+    pub index_sort: IndexSort,
+    /// Clone URLs shown for every repository, where `{repo}` is replaced by
+    /// the repository name
+    pub clone_urls: Vec<String>,
+    pub repos: HashMap<String, RepoConfig>,
+}
+
+/// Order of the repositories within each section of the index
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IndexSort {
+    #[default]
+    Name,
+    /// Most recently changed first
+    Age,
+    Owner,
+}
+
+/// An address to listen on, written `<ip>:<port>` for TCP or `unix:<path>`
+/// for a Unix domain socket
+#[derive(Clone, Debug)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for ListenAddr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.strip_prefix("unix:") {
+            Some("") => bail!("missing socket path"),
+            Some(path) => Ok(ListenAddr::Unix(PathBuf::from(path))),
+            None => Ok(ListenAddr::Tcp(s.parse()?)),
+        }
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => write!(f, "{}", addr),
+            ListenAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// Settings of a single repository, from a `[repos.<name>]` table
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RepoConfig {
+    pub description: Option<String>,
+    pub owner: Option<String>,
+    #[serde(default)]
+    pub hidden: bool,
+    pub section: Option<String>,
+    /// Revision used instead of HEAD when no ref is given in the URL
+    pub default_branch: Option<String>,
+    /// Overrides `log_diffstat` for this repository
+    pub log_diffstat: Option<bool>,
+}
+
+/// Settings given on the command line, which take precedence over both the
+/// environment and the configuration file
+#[derive(Default)]
+pub struct Overrides {
+    pub dir: Option<String>,
+    pub bind: Option<IpAddr>,
+    pub port: Option<u16>,
+    pub listen: Vec<ListenAddr>,
+    pub socket_mode: Option<u32>,
+}
+
+/// Contents of the configuration file, where every setting is optional
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    dir: Option<String>,
+    scan_depth: Option<usize>,
+    project_list: Option<String>,
+    export_ok: Option<bool>,
+    bind: Option<IpAddr>,
+    port: Option<u16>,
+    listen: Option<Vec<String>>,
+    socket_mode: Option<String>,
+    git_workers: Option<usize>,
+    /// In seconds
+    request_timeout: Option<u64>,
+    repo_pool_size: Option<usize>,
+    diffstat_cache_size: Option<usize>,
+    cache_dir: Option<String>,
+    log_page_size: Option<usize>,
+    log_max_page_size: Option<usize>,
+    log_diffstat: Option<bool>,
+    feed_size: Option<usize>,
+    site_title: Option<String>,
+    index_sort: Option<IndexSort>,
+    clone_urls: Option<Vec<String>>,
+    #[serde(default)]
+    repos: HashMap<String, RepoConfig>,
 }
 
 impl Config {
-    pub fn load() -> Self {
-        let dir = env::var("RITZ_DIR").unwrap_or("./".to_string());
-        let port = env::var("RITZ_PORT")
-            .unwrap_or(STD_PORT.to_string())
-            .parse::<u16>()
-            .unwrap();
-        Config { dir, port }
+    /// Returns the configuration read by [Config::init] at startup
+    pub fn load() -> &'static Self {
+        CONFIG.get().expect("configuration not initialized")
+    }
+
+    /// Reads the configuration file at `path`, or at `RITZ_CONFIG` if no path
+    /// is given, applies the `RITZ_*` environment and then the command line
+    /// overrides on top of it and validates the result before making it
+    /// available to [Config::load]
+    pub fn init(
+        path: Option<&str>,
+        overrides: Overrides,
+    ) -> Result<&'static Self> {
+        let path = path.map(|p| p.to_string()).or(env::var("RITZ_CONFIG").ok());
+        let file = match &path {
+            Some(path) => {
+                let contents = std::fs::read_to_string(path)
+                    .with_context(|| format!("Cannot read config {}", path))?;
+                toml::from_str::<ConfigFile>(&contents)
+                    .with_context(|| format!("Invalid config {}", path))?
+            }
+            None => ConfigFile::default(),
+        };
+
+        let listen =
+            listen_addrs(&overrides, file.listen, file.bind, file.port)?;
+        let log_page_size = env_var("RITZ_LOG_PAGE_SIZE")?
+            .or(file.log_page_size)
+            .unwrap_or(STD_LOG_PAGE_SIZE);
+        let config = Config {
+            dir: overrides
+                .dir
+                .or(env::var("RITZ_DIR").ok())
+                .or(file.dir)
+                .unwrap_or("./".to_string()),
+            scan_depth: env_var("RITZ_SCAN_DEPTH")?
+                .or(file.scan_depth)
+                .unwrap_or(STD_SCAN_DEPTH),
+            project_list: env::var("RITZ_PROJECT_LIST")
+                .ok()
+                .or(file.project_list),
+            export_ok: env_var("RITZ_EXPORT_OK")?
+                .or(file.export_ok)
+                .unwrap_or(false),
+            listen,
+            socket_mode: match overrides.socket_mode {
+                Some(mode) => mode,
+                None => env::var("RITZ_SOCKET_MODE")
+                    .ok()
+                    .or(file.socket_mode)
+                    .map(|mode| parse_mode(&mode))
+                    .transpose()?
+                    .unwrap_or(STD_SOCKET_MODE),
+            },
+            git_workers: match env_var("RITZ_GIT_WORKERS")?.or(file.git_workers)
+            {
+                Some(workers) => workers,
+                None => std::thread::available_parallelism()?.get(),
+            },
+            request_timeout: Duration::from_secs(
+                env_var("RITZ_REQUEST_TIMEOUT")?
+                    .or(file.request_timeout)
+                    .unwrap_or(STD_REQUEST_TIMEOUT),
+            ),
+            repo_pool_size: env_var("RITZ_REPO_POOL_SIZE")?
+                .or(file.repo_pool_size)
+                .unwrap_or(STD_REPO_POOL_SIZE),
+            diffstat_cache_size: env_var("RITZ_DIFFSTAT_CACHE_SIZE")?
+                .or(file.diffstat_cache_size)
+                .unwrap_or(STD_DIFFSTAT_CACHE_SIZE),
+            cache_dir: env::var("RITZ_CACHE_DIR").ok().or(file.cache_dir),
+            log_page_size,
+            log_max_page_size: env_var("RITZ_LOG_MAX_PAGE_SIZE")?
+                .or(file.log_max_page_size)
+                .unwrap_or(log_page_size.saturating_mul(STD_LOG_MAX_PAGES)),
+            log_diffstat: env_var("RITZ_LOG_DIFFSTAT")?
+                .or(file.log_diffstat)
+                .unwrap_or(STD_LOG_DIFFSTAT),
+            feed_size: env_var("RITZ_FEED_SIZE")?
+                .or(file.feed_size)
+                .unwrap_or(STD_FEED_SIZE),
+            site_title: file.site_title.unwrap_or(STD_SITE_TITLE.to_string()),
+            index_sort: file.index_sort.unwrap_or_default(),
+            clone_urls: file
+                .clone_urls
+                .unwrap_or(vec![STD_CLONE_URL.to_string()]),
+            repos: file.repos,
+        };
+        config.validate().with_context(|| match &path {
+            Some(path) => format!("Invalid config {}", path),
+            None => "Invalid config".to_string(),
+        })?;
+        Ok(CONFIG.get_or_init(|| config))
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.listen.is_empty() {
+            bail!("listen: must not be empty");
+        }
+        if self.socket_mode > 0o777 {
+            bail!(
+                "socket_mode: {:o} is not a permission mode",
+                self.socket_mode
+            );
+        }
+        if !std::path::Path::new(&self.dir).is_dir() {
+            bail!("dir: {} is not a directory", self.dir);
+        }
+        if let Some(path) = &self.project_list {
+            std::fs::metadata(path).with_context(|| {
+                format!("project_list: cannot read {}", path)
+            })?;
+        }
+        if let Some(dir) = &self.cache_dir {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("cache_dir: cannot create {}", dir))?;
+        }
+        if self.scan_depth == 0 {
+            bail!("scan_depth: must be at least 1");
+        }
+        if self.git_workers == 0 {
+            bail!("git_workers: must be at least 1");
+        }
+        if self.request_timeout.is_zero() {
+            bail!("request_timeout: must be at least 1");
+        }
+        if self.log_page_size == 0 {
+            bail!("log_page_size: must be at least 1");
+        }
+        if self.log_max_page_size < self.log_page_size {
+            bail!("log_max_page_size: must be at least log_page_size");
+        }
+        for url in &self.clone_urls {
+            if !url.contains("{repo}") {
+                bail!("clone_urls: {} does not contain {{repo}}", url);
+            }
+        }
+        for (name, repo) in &self.repos {
+            if name.split('/').any(|s| matches!(s, "" | "." | "..")) {
+                bail!("repos.{}: not a repository name", name);
+            }
+            if name.split('/').count() > self.scan_depth {
+                bail!("repos.{}: deeper than scan_depth", name);
+            }
+            if repo.default_branch.as_deref() == Some("") {
+                bail!("repos.{}.default_branch: must not be empty", name);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the settings of the repository `repo`, if it has any
+    pub fn repo(&self, repo: &str) -> Option<&RepoConfig> {
+        self.repos.get(repo)
+    }
+
+    /// Returns whether the log of `repo` shows diffstats
+    pub fn log_diffstat(&self, repo: &str) -> bool {
+        self.repo(repo)
+            .and_then(|r| r.log_diffstat)
+            .unwrap_or(self.log_diffstat)
+    }
+
+    /// Returns the revision to show for `repo` when none is given
+    pub fn default_rev(&self, repo: &str) -> &str {
+        self.repo(repo)
+            .and_then(|r| r.default_branch.as_deref())
+            .unwrap_or("HEAD")
+    }
+}
+
+/// Returns the addresses to listen on: those given by the first of the
+/// command line, `RITZ_LISTEN` and the file that has any, and otherwise the
+/// single address made of `bind` and `port`
+fn listen_addrs(
+    overrides: &Overrides,
+    file_listen: Option<Vec<String>>,
+    file_bind: Option<IpAddr>,
+    file_port: Option<u16>,
+) -> Result<Vec<ListenAddr>> {
+    if !overrides.listen.is_empty() {
+        return Ok(overrides.listen.clone());
+    }
+    let listen = match env::var("RITZ_LISTEN") {
+        Ok(value) => {
+            Some(value.split(',').map(|s| s.trim().to_string()).collect())
+        }
+        Err(_) => file_listen,
+    };
+    if let Some(listen) = listen {
+        return listen
+            .iter()
+            .map(|s| {
+                s.parse()
+                    .with_context(|| format!("listen: invalid address {:?}", s))
+            })
+            .collect();
+    }
+    let bind = overrides
+        .bind
+        .or(env_var("RITZ_BIND")?)
+        .or(file_bind)
+        .unwrap_or(IpAddr::V6(Ipv6Addr::LOCALHOST));
+    let port = overrides
+        .port
+        .or(env_var("RITZ_PORT")?)
+        .or(file_port)
+        .unwrap_or(STD_PORT);
+    Ok(vec![ListenAddr::Tcp(SocketAddr::new(bind, port))])
+}
+
+/// Parses a permission mode written in octal, such as `660`
+pub fn parse_mode(mode: &str) -> Result<u32> {
+    u32::from_str_radix(mode, 8)
+        .map_err(|e| anyhow!("socket_mode: invalid mode {:?}: {}", mode, e))
+}
+
+/// Parses the environment variable `name` if it is set
+fn env_var<T: FromStr>(name: &str) -> Result<Option<T>>
+where
+    T::Err: std::fmt::Display,
+{
+    match env::var(name) {
+        Ok(value) => value
+            .parse::<T>()
+            .map(Some)
+            .map_err(|e| anyhow!("{}: invalid value {:?}: {}", name, value, e)),
+        Err(_) => Ok(None),
     }
 }
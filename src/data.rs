@@ -1,5 +1,18 @@
+use crate::config::Config;
+use crate::discover::{is_exported, resolve_repo};
+use crate::error::ClientError;
+use crate::index::{compute_entry, Index};
+use crate::state::{AppState, PooledRepo};
 use anyhow::{anyhow, Result};
-use git2::{Diff, DiffFindOptions, Oid, Patch, Repository, Signature, Tree};
+use git2::{
+    Commit, Delta, Diff, DiffFindOptions, Object, Patch, Repository, Signature,
+    Time, Tree,
+};
+use std::path::PathBuf;
+
+/// Start of the placeholder `git init` writes to `description`, which is
+/// treated as no description at all, like gitweb does
+const DEFAULT_DESCRIPTION: &str = "Unnamed repository;";
 
 pub struct DeltaInfo<'a> {
     pub patch: Patch<'a>,
@@ -7,14 +20,19 @@ pub struct DeltaInfo<'a> {
     pub del_count: usize,
 }
 
-pub struct CommitInfo<'a> {
+/// What the commit object itself tells, which is cheap to get as opposed to
+/// the diff of [CommitInfo] or the [DiffStat]
+pub struct CommitHeader {
     pub oid: String,
     pub parentoid: Option<String>,
-    pub author: Signature<'a>,
+    pub author: Signature<'static>,
+    pub committer: Signature<'static>,
     pub summary: Option<String>,
     pub msg: Option<String>,
-    pub commit_tree: Tree<'a>,
-    pub parent_tree: Option<Tree<'a>>,
+}
+
+pub struct CommitInfo<'a> {
+    pub header: CommitHeader,
     pub diff: Diff<'a>,
     pub deltas: Vec<DeltaInfo<'a>>,
     pub add_count: usize,
@@ -22,15 +40,115 @@ pub struct CommitInfo<'a> {
     pub file_count: usize,
 }
 
-pub fn get_commitinfo(repo: &Repository, oid: String) -> Result<CommitInfo> {
-    let commit = repo.find_commit(Oid::from_str(&oid)?)?;
+/// Number of files a commit changes and of lines it adds and removes,
+/// compared to its first parent
+#[derive(Clone, Copy)]
+pub struct DiffStat {
+    pub files: usize,
+    pub additions: usize,
+    pub deletions: usize,
+}
+
+/// Opens the repository named `name` under `Config.dir`, as long as it is
+/// one that discovery finds there and that may be exported. This is the only
+/// place where names coming from URLs are turned into paths. Repositories
+/// are taken from the pool of [AppState] when possible, their path having
+/// been checked when first opened. Whether they may be exported is checked
+/// every time, as the project list or the export file may have changed.
+pub fn open_repo(name: &str) -> Result<PooledRepo> {
+    let config = Config::load();
+    let not_found = || ClientError::RepoNotFound(name.to_string());
+    let repo = AppState::load().repo(name, || {
+        let root = std::path::Path::new(&config.dir);
+        resolve_repo(root, name, config.scan_depth)
+            .and_then(|path| Repository::open(path).ok())
+            .ok_or_else(not_found)
+    })?;
+    match is_exported(config, name, &repo) {
+        true => Ok(repo),
+        false => Err(not_found().into()),
+    }
+}
+
+/// Returns the commit `rev` resolves to
+pub fn resolve_commit<'r>(
+    repo: &'r Repository,
+    rev: &str,
+) -> Result<Commit<'r>> {
+    repo.revparse_single(rev)
+        .and_then(|obj| obj.peel_to_commit())
+        .map_err(|_| ClientError::RefNotFound(rev.to_string()).into())
+}
+
+/// Returns the object at `path` in the tree of `commit`, or the tree itself
+/// if `path` is empty
+pub fn find_path<'r>(
+    repo: &'r Repository,
+    commit: &Commit<'r>,
+    path: &str,
+) -> Result<Object<'r>> {
+    let tree = commit.tree()?;
+    if path.is_empty() {
+        return Ok(tree.into_object());
+    }
+    tree.get_path(std::path::Path::new(path))
+        .and_then(|entry| entry.to_object(repo))
+        .map_err(|_| ClientError::PathNotFound(path.to_string()).into())
+}
+
+/// Returns the path the file at `path` in the tree `new` was renamed from
+/// since the tree `old`, if it was
+pub fn rename_source(
+    repo: &Repository,
+    old: &Tree,
+    new: &Tree,
+    path: &std::path::Path,
+) -> Result<Option<PathBuf>> {
+    let mut diff = repo.diff_tree_to_tree(Some(old), Some(new), None)?;
+    diff.find_similar(Some(DiffFindOptions::new().renames(true)))?;
+    let source = diff
+        .deltas()
+        .filter(|d| d.status() == Delta::Renamed)
+        .find(|d| d.new_file().path() == Some(path))
+        .and_then(|d| d.old_file().path().map(|p| p.to_path_buf()));
+    Ok(source)
+}
+
+/// Looks up the commit `oid`, which must be a full or abbreviated id
+pub fn find_commit<'r>(repo: &'r Repository, oid: &str) -> Result<Commit<'r>> {
+    let is_hex = oid.bytes().all(|b| b.is_ascii_hexdigit());
+    if oid.is_empty() || oid.len() > 64 || !is_hex {
+        return Err(ClientError::BadObjectId(oid.into()).into());
+    }
+    // Abbreviated ids are looked up by prefix, which `Oid::from_str` would
+    // pad with zeros instead
+    repo.revparse_single(oid)
+        .and_then(|obj| obj.peel_to_commit())
+        .map_err(|_| ClientError::CommitNotFound(oid.into()).into())
+}
+
+impl CommitHeader {
+    pub fn new(commit: &Commit) -> Self {
+        CommitHeader {
+            oid: commit.id().to_string(),
+            parentoid: commit.parent_id(0).ok().map(|id| id.to_string()),
+            author: commit.author().to_owned(),
+            committer: commit.committer().to_owned(),
+            summary: commit.summary().map(|s| s.into()),
+            msg: commit.message().map(|s| s.into()),
+        }
+    }
+}
+
+pub fn get_commitinfo(
+    repo: &Repository,
+    oid: String,
+) -> Result<CommitInfo<'_>> {
+    let commit = find_commit(repo, &oid)?;
+    let header = CommitHeader::new(&commit);
     let parent = commit.parent(0).ok();
-    let parentoid = parent.as_ref().map(|c| c.id().to_string());
-    let author = commit.author().to_owned();
-    let summary = commit.summary().map(|s| s.into());
-    let msg = commit.message().map(|s| s.into());
     let commit_tree = commit.tree()?;
-    let parent_tree = parent.map(|c| c.tree().ok()).flatten();
+    let parent_tree = parent.and_then(|c| c.tree().ok());
     let mut diff = Repository::diff_tree_to_tree(
         repo,
         parent_tree.as_ref(),
@@ -62,13 +180,7 @@ pub fn get_commitinfo(repo: &Repository, oid: String) -> Result<CommitInfo> {
     }
 
     Ok(CommitInfo {
-        oid,
-        parentoid,
-        author,
-        summary,
-        msg,
-        commit_tree,
-        parent_tree,
+        header,
         diff,
         deltas,
         add_count,
@@ -76,3 +188,112 @@ pub fn get_commitinfo(repo: &Repository, oid: String) -> Result<CommitInfo> {
         file_count,
     })
 }
+
+/// Returns the diffstat of `commit`, counted the same way as by
+/// [get_commitinfo] but without building patches. It is cached in memory by
+/// commit id, and on disk in the [Index] of the repository if there is one.
+pub fn get_diffstat(repo: &PooledRepo, commit: &Commit) -> Result<DiffStat> {
+    AppState::load().diffstat(commit.id(), || {
+        let entry = match Index::open(repo.name()) {
+            Some(index) => index.entry(repo, commit)?,
+            None => compute_entry(repo, commit)?,
+        };
+        Ok(entry.stat())
+    })
+}
+
+impl CommitInfo<'_> {
+    pub fn diffstat(&self) -> DiffStat {
+        DiffStat {
+            files: self.file_count,
+            additions: self.add_count,
+            deletions: self.del_count,
+        }
+    }
+}
+
+pub struct RepoInfo {
+    pub description: Option<String>,
+    pub owner: Option<String>,
+    pub section: Option<String>,
+    pub clone_urls: Vec<String>,
+    /// Commit time of the newest commit across all branches
+    pub last_change: Option<Time>,
+}
+
+/// Gathers the description, owner, section and clone URLs of the repository
+/// `name` from the ritz configuration first, then from the `description`,
+/// `owner`, `url` and `cloneurl` files read by stagit and gitweb and the
+/// `gitweb.*` and `cgit.section` settings of the repository
+pub fn get_repoinfo(
+    config: &Config,
+    repo: &Repository,
+    name: &str,
+) -> RepoInfo {
+    let repoconfig = config.repo(name);
+    let gitconfig = repo.config().ok();
+    let gitconfig_str = |key: &str| {
+        gitconfig
+            .as_ref()
+            .and_then(|c| c.get_string(key).ok())
+            .filter(|s| !s.trim().is_empty())
+    };
+
+    let description = repoconfig
+        .and_then(|r| r.description.clone())
+        .or(read_git_file(repo, "description")
+            .filter(|s| !s.starts_with(DEFAULT_DESCRIPTION)))
+        .or(gitconfig_str("gitweb.description"));
+    let owner = repoconfig
+        .and_then(|r| r.owner.clone())
+        .or(gitconfig_str("gitweb.owner"))
+        .or(read_git_file(repo, "owner"));
+    let section = repoconfig
+        .and_then(|r| r.section.clone())
+        .or(gitconfig_str("gitweb.category"))
+        .or(gitconfig_str("cgit.section"));
+
+    let mut clone_urls = ["url", "cloneurl"]
+        .iter()
+        .filter_map(|file| read_git_file(repo, file))
+        .flat_map(|s| {
+            s.lines().map(|l| l.trim().to_string()).collect::<Vec<_>>()
+        })
+        .filter(|url| !url.is_empty())
+        .collect::<Vec<_>>();
+    clone_urls.dedup();
+    if clone_urls.is_empty() {
+        clone_urls = config
+            .clone_urls
+            .iter()
+            .map(|url| url.replace("{repo}", name))
+            .collect();
+    }
+
+    let last_change = repo
+        .references_glob("refs/heads/*")
+        .into_iter()
+        .flatten()
+        .filter_map(|rf| rf.ok()?.peel_to_commit().ok())
+        .map(|commit| commit.time())
+        .max_by_key(|time| time.seconds());
+
+    RepoInfo {
+        description,
+        owner,
+        section,
+        clone_urls,
+        last_change,
+    }
+}
+
+/// Returns the trimmed contents of the file `name` in the git directory of
+/// `repo`, unless it is missing or empty
+fn read_git_file(repo: &Repository, name: &str) -> Option<String> {
+    let contents = std::fs::read_to_string(repo.path().join(name)).ok()?;
+    let contents = contents.trim();
+    match contents.is_empty() {
+        true => None,
+        false => Some(contents.to_string()),
+    }
+}
@@ -0,0 +1,361 @@
+use crate::config::Config;
+use crate::data::open_repo;
+use crate::error::{error_response, ClientError, API_PREFIX};
+use crate::util::urldecode;
+use crate::worker::blocking;
+use axum::{
+    extract::{
+        rejection::RawPathParamsRejection, OriginalUri, RawPathParams,
+        Request as AxumRequest,
+    },
+    http::{Request, Uri},
+    middleware::Next,
+    response::Response,
+};
+use git2::Repository;
+use std::path::{Path, PathBuf};
+
+/// File whose presence in a git directory allows exporting it, as with
+/// `git daemon`
+const EXPORT_OK: &str = "git-daemon-export-ok";
+
+/// Prefix of the API routes taking a repository name
+const API_REPOS: &str = "/api/v1/repos/";
+
+/// Prefix of the static assets, which no repository name can start
+const STATIC_PREFIX: &str = "/static/";
+
+/// Repositories found under a directory, named by their path relative to it
+#[derive(Default)]
+pub struct Discovery {
+    pub repos: Vec<String>,
+    /// Directories that are neither repositories nor contain any, with the
+    /// error opening them as one
+    pub errors: Vec<(String, git2::Error)>,
+}
+
+/// Looks for repositories under `dir`, descending into directories that are
+/// not repositories themselves at most `depth` levels deep
+pub fn discover(dir: &str, depth: usize) -> Discovery {
+    let mut discovery = Discovery::default();
+    let root = Path::new(dir);
+    let root = root.canonicalize().unwrap_or(root.to_path_buf());
+    scan(&root, &root, "", depth, &mut discovery);
+    discovery.repos.sort();
+    discovery
+}
+
+/// Scans `dir`, whose repositories are named with `prefix`, and returns
+/// whether it contains any
+fn scan(
+    root: &Path,
+    dir: &Path,
+    prefix: &str,
+    depth: usize,
+    found: &mut Discovery,
+) -> bool {
+    let mut any = false;
+    let entries = std::fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok());
+    for entry in entries {
+        let Ok(name) = entry.file_name().into_string() else {
+            continue;
+        };
+        let path = entry.path();
+        if !path.is_dir() {
+            continue;
+        }
+        let name = format!("{}{}", prefix, name);
+        let is_link = entry.file_type().is_ok_and(|t| t.is_symlink());
+        match Repository::open(&path) {
+            Ok(_) if is_link && !within(root, &path) => {
+                let e = git2::Error::from_str("Symbolic link leaves the root");
+                found.errors.push((name, e));
+            }
+            Ok(_) => {
+                found.repos.push(name);
+                any = true;
+            }
+            Err(e) => {
+                // Symbolic links are only followed to repositories, so that
+                // the scan cannot loop
+                let nested = depth > 1
+                    && !is_link
+                    && scan(
+                        root,
+                        &path,
+                        &format!("{}/", name),
+                        depth - 1,
+                        found,
+                    );
+                if nested {
+                    any = true;
+                } else {
+                    found.errors.push((name, e));
+                }
+            }
+        }
+    }
+    any
+}
+
+/// Returns the path of the repository `name` under `root`, provided that
+/// discovery down to `depth` levels would find it there. Absolute names,
+/// names with empty, `.` or `..` components and names leading through a
+/// symbolic link or a repository are refused, and so is a repository that is
+/// a symbolic link leading out of `root`.
+pub fn resolve_repo(root: &Path, name: &str, depth: usize) -> Option<PathBuf> {
+    let root = root.canonicalize().ok()?;
+    let segments = name.split('/').collect::<Vec<_>>();
+    if segments.len() > depth {
+        return None;
+    }
+    let mut path = root.clone();
+    for (i, segment) in segments.iter().enumerate() {
+        if matches!(*segment, "" | "." | "..") {
+            return None;
+        }
+        path.push(segment);
+        if i + 1 < segments.len() {
+            let meta = std::fs::symlink_metadata(&path).ok()?;
+            if !meta.is_dir() || Repository::open(&path).is_ok() {
+                return None;
+            }
+        }
+    }
+    let path = path.canonicalize().ok()?;
+    if !path.starts_with(&root) || !path.is_dir() {
+        return None;
+    }
+    Repository::open(&path).is_ok().then_some(path)
+}
+
+/// Returns whether `path` leads to somewhere under `root` once symbolic
+/// links are followed
+fn within(root: &Path, path: &Path) -> bool {
+    path.canonicalize().is_ok_and(|path| path.starts_with(root))
+}
+
+/// Returns whether the repository `name`, found under `config.dir` and
+/// opened as `repo`, may be exposed according to `project_list` and
+/// `export_ok`
+pub fn is_exported(config: &Config, name: &str, repo: &Repository) -> bool {
+    if let Some(list) = &config.project_list {
+        let Ok(projects) = std::fs::read_to_string(list) else {
+            return false;
+        };
+        // Lines may go on with the owner, as in the gitweb format
+        let listed = projects
+            .lines()
+            .filter_map(|line| line.split_whitespace().next())
+            .any(|project| same_repo(&urldecode(project), name));
+        if !listed {
+            return false;
+        }
+    }
+    !config.export_ok || repo.path().join(EXPORT_OK).exists()
+}
+
+/// Returns whether the project list entry `project` names the repository
+/// `name`, either of them possibly lacking the `.git` suffix
+fn same_repo(project: &str, name: &str) -> bool {
+    let project = project.trim_matches('/');
+    project.trim_end_matches(".git") == name.trim_end_matches(".git")
+}
+
+/// Answers requests for a repository that is not exported exactly as if it
+/// did not exist. The repository is opened the same way as by the handlers,
+/// which then take it from the pool.
+pub async fn require_exported(
+    params: Result<RawPathParams, RawPathParamsRejection>,
+    OriginalUri(uri): OriginalUri,
+    request: AxumRequest,
+    next: Next,
+) -> Response {
+    let Ok(params) = params else {
+        return error_response(&uri, ClientError::InvalidUtf8);
+    };
+    let repo = params.iter().find(|(key, _)| *key == "repo");
+    if let Some((_, repo)) = repo {
+        let repo = repo.to_string();
+        let opened = blocking(move || open_repo(&repo).map(drop)).await;
+        if let Err(e) = opened {
+            return error_response(&uri, e);
+        }
+    }
+    next.run(request).await
+}
+
+/// Rewrites the path of requests for a repository nested in subdirectories,
+/// such as `/team/project/log`, so that its name becomes a single segment
+/// that the `/:repo` routes match, as in `/team%2Fproject/log`. The name
+/// ends at the first leading run of segments that is a repository.
+pub fn route_nested_repo<B>(mut request: Request<B>) -> Request<B> {
+    let uri = request.uri();
+    let path = uri.path();
+    let (base, rest) = match path.strip_prefix(API_REPOS) {
+        Some(rest) => (API_REPOS, rest),
+        None => ("/", &path[1.min(path.len())..]),
+    };
+    let reserved = [API_PREFIX, STATIC_PREFIX];
+    if base == "/" && reserved.iter().any(|p| path.starts_with(p)) {
+        return request;
+    }
+    let Some(len) = nested_repo_len(rest) else {
+        return request;
+    };
+    let segments = rest.split('/').collect::<Vec<_>>();
+    let mut new_path = format!("{}{}", base, segments[..len].join("%2F"));
+    for segment in &segments[len..] {
+        new_path.push('/');
+        new_path.push_str(segment);
+    }
+    if let Some(query) = uri.query() {
+        new_path.push('?');
+        new_path.push_str(query);
+    }
+    if let Ok(new_uri) = new_path.parse::<Uri>() {
+        *request.uri_mut() = new_uri;
+    }
+    request
+}
+
+/// Returns the number of leading segments of `path` naming a repository
+/// under `Config.dir`, if there are more than one. As this runs for every
+/// request on the async runtime, repositories are told by their layout
+/// rather than opened; handlers open them properly in any case.
+fn nested_repo_len(path: &str) -> Option<usize> {
+    let config = Config::load();
+    let segments = path.split('/').collect::<Vec<_>>();
+    let mut dir = Path::new(&config.dir).to_path_buf();
+    for (i, segment) in segments.iter().take(config.scan_depth).enumerate() {
+        let segment = urldecode(segment);
+        if matches!(segment.as_str(), "" | "." | "..") || segment.contains('/')
+        {
+            return None;
+        }
+        dir.push(segment);
+        if !dir.is_dir() {
+            return None;
+        }
+        if looks_like_repo(&dir) {
+            return (i > 0).then_some(i + 1);
+        }
+    }
+    None
+}
+
+/// Returns whether `dir` looks like a repository, either bare or with a
+/// `.git` entry, without opening it
+fn looks_like_repo(dir: &Path) -> bool {
+    dir.join(".git").exists()
+        || (dir.join("HEAD").is_file() && dir.join("objects").is_dir())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    /// A directory holding `root`, the directory repositories are served
+    /// from, and `outside`, a repository that must never be reachable
+    struct Fixture {
+        base: PathBuf,
+        root: PathBuf,
+    }
+
+    impl Fixture {
+        fn new(test: &str) -> Self {
+            let base = std::env::temp_dir().join(format!(
+                "ritz-{}-{}",
+                test,
+                std::process::id()
+            ));
+            let _ = std::fs::remove_dir_all(&base);
+            let root = base.join("root");
+            Repository::init(root.join("plain")).unwrap();
+            Repository::init_bare(root.join("team/proj.git")).unwrap();
+            Repository::init_bare(base.join("outside")).unwrap();
+            std::fs::create_dir_all(root.join("empty")).unwrap();
+            Fixture { base, root }
+        }
+
+        fn resolve(&self, name: &str) -> Option<PathBuf> {
+            resolve_repo(&self.root, name, 3)
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.base);
+        }
+    }
+
+    #[test]
+    fn resolves_discovered_repos() {
+        let fx = Fixture::new("discovered");
+        for name in discover(fx.root.to_str().unwrap(), 3).repos {
+            assert!(fx.resolve(&name).is_some(), "{}", name);
+        }
+        assert!(fx.resolve("plain").is_some());
+        assert!(fx.resolve("team/proj.git").is_some());
+    }
+
+    #[test]
+    fn refuses_traversal() {
+        let fx = Fixture::new("traversal");
+        for name in [
+            "",
+            ".",
+            "..",
+            "../outside",
+            "team/../plain",
+            "team/../../outside",
+            "./plain",
+            "team//proj.git",
+            "team/proj.git/",
+            "/plain",
+            "empty",
+            "missing",
+        ] {
+            assert_eq!(fx.resolve(name), None, "{:?}", name);
+        }
+        let absolute = fx.base.join("outside");
+        assert_eq!(fx.resolve(absolute.to_str().unwrap()), None);
+    }
+
+    #[test]
+    fn refuses_repos_inside_repos() {
+        let fx = Fixture::new("inside");
+        assert_eq!(fx.resolve("plain/.git"), None);
+        Repository::init_bare(fx.root.join("team/proj.git/sub")).unwrap();
+        assert_eq!(fx.resolve("team/proj.git/sub"), None);
+    }
+
+    #[test]
+    fn refuses_names_deeper_than_the_scan() {
+        let fx = Fixture::new("depth");
+        assert_eq!(resolve_repo(&fx.root, "team/proj.git", 1), None);
+    }
+
+    #[test]
+    fn refuses_symlinks_leaving_the_root() {
+        let fx = Fixture::new("symlinks");
+        symlink(fx.base.join("outside"), fx.root.join("escape")).unwrap();
+        symlink(&fx.base, fx.root.join("up")).unwrap();
+        symlink(fx.root.join("team"), fx.root.join("alias")).unwrap();
+        symlink(fx.root.join("plain"), fx.root.join("link")).unwrap();
+        assert_eq!(fx.resolve("escape"), None);
+        assert_eq!(fx.resolve("up/outside"), None);
+        assert_eq!(fx.resolve("up/root/plain"), None);
+        assert_eq!(fx.resolve("alias/proj.git"), None);
+        // A link to a repository under the root is listed, so it is served
+        assert!(fx.resolve("link").is_some());
+
+        let repos = discover(fx.root.to_str().unwrap(), 3).repos;
+        assert!(repos.contains(&"link".to_string()));
+        assert!(!repos.contains(&"escape".to_string()));
+    }
+}
@@ -1,17 +1,128 @@
+use crate::handlers::{footer, header};
+use crate::util::xmlencode;
 use axum::{
-    http::StatusCode,
-    response::{IntoResponse, Response},
+    http::{StatusCode, Uri},
+    response::{Html, IntoResponse, Response},
+    Json,
 };
+use git2::ErrorCode;
+use serde::Serialize;
+use std::fmt;
+
+/// Prefix of the API routes, whose errors are answered in JSON
+pub const API_PREFIX: &str = "/api/";
+
+/// Errors caused by the request rather than by the server, which are
+/// answered with a 4xx status
+#[derive(Debug)]
+pub enum ClientError {
+    RepoNotFound(String),
+    RefNotFound(String),
+    CommitNotFound(String),
+    PathNotFound(String),
+    PageNotFound,
+    BadObjectId(String),
+    BadParameter(String),
+    InvalidUtf8,
+}
+
+impl ClientError {
+    fn status(&self) -> StatusCode {
+        match self {
+            ClientError::BadObjectId(_)
+            | ClientError::BadParameter(_)
+            | ClientError::InvalidUtf8 => StatusCode::BAD_REQUEST,
+            _ => StatusCode::NOT_FOUND,
+        }
+    }
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ClientError::RepoNotFound(repo) => {
+                write!(f, "Repository not found: {}", repo)
+            }
+            ClientError::RefNotFound(rev) => {
+                write!(f, "Revision not found: {}", rev)
+            }
+            ClientError::CommitNotFound(oid) => {
+                write!(f, "Commit not found: {}", oid)
+            }
+            ClientError::PathNotFound(path) => {
+                write!(f, "Path not found: {}", path)
+            }
+            ClientError::PageNotFound => write!(f, "Page not found"),
+            ClientError::BadObjectId(oid) => {
+                write!(f, "Invalid object id: {}", oid)
+            }
+            ClientError::BadParameter(msg) => write!(f, "{}", msg),
+            ClientError::InvalidUtf8 => write!(f, "Invalid UTF-8"),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+/// Error for a request that did not get an answer within
+/// `Config.request_timeout`, which is answered with a 503
+#[derive(Debug)]
+pub struct Timeout;
+
+impl fmt::Display for Timeout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "The server is too busy to answer, try again later")
+    }
+}
+
+impl std::error::Error for Timeout {}
 
 pub struct AppError(anyhow::Error);
 
+impl AppError {
+    /// Returns the status to answer with and the message to show
+    fn status(&self) -> (StatusCode, String) {
+        let e = &self.0;
+        let status = if let Some(e) = e.downcast_ref::<ClientError>() {
+            e.status()
+        } else if e
+            .downcast_ref::<git2::Error>()
+            .is_some_and(|e| e.code() == ErrorCode::NotFound)
+        {
+            StatusCode::NOT_FOUND
+        } else if e.is::<Timeout>() {
+            StatusCode::SERVICE_UNAVAILABLE
+        } else if e.is::<std::str::Utf8Error>()
+            || e.is::<std::string::FromUtf8Error>()
+        {
+            return (
+                StatusCode::BAD_REQUEST,
+                ClientError::InvalidUtf8.to_string(),
+            );
+        } else {
+            StatusCode::INTERNAL_SERVER_ERROR
+        };
+        match status {
+            StatusCode::INTERNAL_SERVER_ERROR => {
+                eprintln!("ritz: {:#}", e);
+                (status, "Something went wrong".to_string())
+            }
+            _ => (status, e.to_string()),
+        }
+    }
+}
+
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            format!("Something went wrong: {}", self.0),
-        )
-            .into_response()
+        let (status, msg) = self.status();
+        let mut result = header();
+        result.push_str(&format!(
+            "<h1>{}</h1><p>{}</p>",
+            status,
+            xmlencode(&msg)
+        ));
+        result.push_str(footer());
+        (status, Html(result)).into_response()
     }
 }
 
@@ -26,3 +137,41 @@ where
         Self(err.into())
     }
 }
+
+/// Same as [AppError] for the JSON API, answering with a JSON body
+pub struct ApiError(AppError);
+
+#[derive(Serialize)]
+struct ApiErrorBody {
+    status: u16,
+    error: String,
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let (status, error) = self.0.status();
+        let body = ApiErrorBody {
+            status: status.as_u16(),
+            error,
+        };
+        (status, Json(body)).into_response()
+    }
+}
+
+impl<E> From<E> for ApiError
+where
+    E: Into<anyhow::Error>,
+{
+    fn from(err: E) -> Self {
+        Self(AppError::from(err))
+    }
+}
+
+/// Answers with `e` in JSON for the API routes and as a page otherwise,
+/// where `uri` is the URI the request came with
+pub fn error_response(uri: &Uri, e: impl Into<anyhow::Error>) -> Response {
+    match uri.path().starts_with(API_PREFIX) {
+        true => ApiError::from(e.into()).into_response(),
+        false => AppError::from(e.into()).into_response(),
+    }
+}
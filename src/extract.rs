@@ -0,0 +1,68 @@
+use crate::error::{error_response, ClientError};
+use anyhow::anyhow;
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, OriginalUri},
+    http::{request::Parts, StatusCode},
+    response::Response,
+};
+use serde::de::DeserializeOwned;
+
+/// Same as [axum::extract::Path], answering with an error page, or a JSON
+/// error under the API prefix, when the path parameters cannot be parsed
+pub struct Path<T>(pub T);
+
+/// Same as [axum::extract::Query], answering the same as [Path] when the
+/// query string cannot be parsed
+pub struct Query<T>(pub T);
+
+#[async_trait]
+impl<T, S> FromRequestParts<S> for Path<T>
+where
+    T: DeserializeOwned + Send,
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &S,
+    ) -> Result<Self, Self::Rejection> {
+        match axum::extract::Path::from_request_parts(parts, state).await {
+            Ok(axum::extract::Path(value)) => Ok(Path(value)),
+            Err(e) => Err(reject(parts, e.status(), e.body_text())),
+        }
+    }
+}
+
+#[async_trait]
+impl<T, S> FromRequestParts<S> for Query<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &S,
+    ) -> Result<Self, Self::Rejection> {
+        match axum::extract::Query::from_request_parts(parts, state).await {
+            Ok(axum::extract::Query(value)) => Ok(Query(value)),
+            Err(e) => Err(reject(parts, e.status(), e.body_text())),
+        }
+    }
+}
+
+/// Turns the rejection of an axum extractor into the error response of the
+/// route, a 400 unless the route itself is wrong
+fn reject(parts: &Parts, status: StatusCode, msg: String) -> Response {
+    let uri = match parts.extensions.get::<OriginalUri>() {
+        Some(OriginalUri(uri)) => uri,
+        None => &parts.uri,
+    };
+    match status.is_client_error() {
+        true => error_response(uri, ClientError::BadParameter(msg)),
+        false => error_response(uri, anyhow!(msg)),
+    }
+}
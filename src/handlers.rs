@@ -1,119 +1,319 @@
+pub mod api;
 pub mod asset;
+pub mod blame;
+pub mod clone;
 pub mod commit;
+pub mod feed;
 pub mod log;
+pub mod raw;
 pub mod refs;
+pub mod snapshot;
 
-use crate::config::Config;
-use crate::util::xmlencode;
-use axum::{extract::Path, http::header, response::Html};
-use git2::{ObjectType, Repository, Tree};
+use crate::config::{Config, IndexSort};
+use crate::data::{
+    find_path, get_repoinfo, open_repo, resolve_commit, RepoInfo,
+};
+use crate::discover::discover;
+use crate::error::{AppError, ClientError};
+use crate::extract::{Path, Query};
+use crate::util::{print_time_short, urlencode, xmlencode};
+use crate::worker::blocking;
+use anyhow::Result;
+use axum::response::Html;
+use git2::{Commit, ObjectType, Repository, Tree};
+use serde::Deserialize;
 
-pub async fn root() -> Html<String> {
+#[derive(Deserialize)]
+pub struct IndexQuery {
+    sort: Option<IndexSort>,
+}
+
+pub async fn root(
+    Query(query): Query<IndexQuery>,
+) -> Result<Html<String>, AppError> {
+    blocking(move || Ok(render_index(query.sort))).await
+}
+
+/// Renders the list of repositories, sorted by `sort` or else by
+/// `Config.index_sort`
+fn render_index(sort: Option<IndexSort>) -> Html<String> {
     let config = Config::load();
+    let sort = sort.unwrap_or(config.index_sort);
+    let mut repos = Vec::new();
+    for name in list_repos(&config.dir) {
+        if let Ok(repo) = open_repo(&name) {
+            let info = get_repoinfo(config, &repo, &name);
+            repos.push((name, info));
+        }
+    }
+    match sort {
+        IndexSort::Name => (),
+        // Most recently changed first
+        IndexSort::Age => repos.sort_by_key(|(_, info)| {
+            std::cmp::Reverse(info.last_change.map(|t| t.seconds()))
+        }),
+        // Repositories without an owner last
+        IndexSort::Owner => repos.sort_by_key(|(_, info)| {
+            (info.owner.is_none(), info.owner.clone())
+        }),
+    }
+    // Repositories are grouped by section, or by the directory they are in
+    // if they have none, with the top-level ones first. The sort is stable,
+    // so each group keeps the order chosen above.
+    repos.sort_by_key(|(name, info)| group(name, info));
+
     let mut result: Vec<String> = Vec::new();
     result.push(header().to_string());
-    result.push("<span>Repositories</span>".to_string());
+    result.push(format!("<span>{}</span>", xmlencode(&config.site_title)));
+    result.push(
+        "<p>Sort by: <a href=\"/?sort=name\">Name</a> \
+         <a href=\"/?sort=age\">Last change</a> \
+         <a href=\"/?sort=owner\">Owner</a></p>"
+            .to_string(),
+    );
     result.push("<hr/>".to_string());
-    let mut paths = std::fs::read_dir(config.dir)
-        .unwrap()
-        .filter_map(|entry| entry.ok())
-        .map(|entry| entry.path())
-        .filter(|path| Repository::open(path).is_ok())
-        .map(|path| path.into_os_string().into_string().unwrap())
-        .collect::<Vec<String>>();
-    paths.sort();
-    result.push("<table>".to_string());
-    result.push("<thead><tr><td><b>Name</b></td></tr></thread>".to_string());
-    for path in paths {
-        let repo = basename(&path, '/');
-        result.push("<tr><td>".to_string());
-        result.push(format!("<a href=/{}>{}</a>", repo, repo));
-        result.push("</td></td>".to_string());
+    let mut rest = &repos[..];
+    while let Some((name, info)) = rest.first() {
+        let section = group(name, info);
+        let len = rest
+            .iter()
+            .take_while(|(name, info)| group(name, info) == section)
+            .count();
+        let (members, next) = rest.split_at(len);
+        rest = next;
+        if let Some(section) = &section {
+            result.push(format!(
+                "<details open><summary><b>{}</b></summary>",
+                xmlencode(section)
+            ));
+        }
+        result.push(write_repos(members));
+        if section.is_some() {
+            result.push("</details>".to_string());
+        }
     }
-    result.push("</table>".to_string());
     result.push(footer().to_string());
     Html(result.join(""))
 }
 
-pub async fn tree(Path((repo, path)): Path<(String, String)>) -> Html<String> {
+/// Returns the index table listing `repos`
+fn write_repos(repos: &[(String, RepoInfo)]) -> String {
     let mut result: Vec<String> = Vec::new();
+    result.push("<table>".to_string());
+    result.push(
+        "<thead><tr><td><b>Name</b></td><td><b>Description</b></td>\
+        <td><b>Owner</b></td><td><b>Last change</b></td></tr></thread>"
+            .to_string(),
+    );
+    for (repo, info) in repos {
+        result.push("<tr><td>".to_string());
+        result.push(format!(
+            "<a href=\"/{}\">{}</a>",
+            urlencode(repo, "/"),
+            xmlencode(repo)
+        ));
+        result.push("</td><td>".to_string());
+        if let Some(description) = &info.description {
+            result.push(xmlencode(description));
+        }
+        result.push("</td><td>".to_string());
+        if let Some(owner) = &info.owner {
+            result.push(xmlencode(owner));
+        }
+        result.push("</td><td>".to_string());
+        if let Some(time) = info.last_change {
+            let mut date = String::new();
+            if print_time_short(&mut date, time).is_ok() {
+                result.push(date);
+            }
+        }
+        result.push("</td></tr>".to_string());
+    }
+    result.push("</table>".to_string());
+    result.join("")
+}
+
+/// Answers requests matching no route
+pub async fn not_found() -> AppError {
+    ClientError::PageNotFound.into()
+}
+
+/// Returns the heading `repo` is listed under on the index: its section, or
+/// else the directory it is in
+fn group(repo: &str, info: &RepoInfo) -> Option<String> {
+    info.section
+        .clone()
+        .or(repo.rsplit_once('/').map(|(dir, _)| dir.to_string()))
+}
+
+/// Returns the sorted names of the exported repositories under `dir`,
+/// leaving out the ones configured as hidden
+pub fn list_repos(dir: &str) -> Vec<String> {
     let config = Config::load();
-    result.push(format!("<h1>{repo}</h1>"));
-    result.push(format!("<span>git clone git://{repo}.git</span>"));
-    result.push(format!(
-        "<span>
-    <a href=\"/{repo}/log\">Log</a>
-    <a href=\"/{repo}/tree\">Tree</a>
-    <a href=\"/{repo}/refs\">Refs</a>
-            </span>"
-    ));
-    result.push("<hr/>".to_string());
+    discover(dir, config.scan_depth)
+        .repos
+        .into_iter()
+        .filter(|repo| !config.repo(repo).is_some_and(|r| r.hidden))
+        .filter(|repo| open_repo(repo).is_ok())
+        .collect()
+}
+
+pub async fn tree_head(
+    Path(repo): Path<String>,
+) -> Result<Html<String>, AppError> {
+    blocking(move || render_tree(&repo, Config::load().default_rev(&repo), ""))
+        .await
+}
+
+pub async fn tree_root(
+    Path((repo, rev)): Path<(String, String)>,
+) -> Result<Html<String>, AppError> {
+    blocking(move || render_tree(&repo, &rev, "")).await
+}
+
+pub async fn tree(
+    Path((repo, rev, path)): Path<(String, String, String)>,
+) -> Result<Html<String>, AppError> {
+    blocking(move || render_tree(&repo, &rev, &path)).await
+}
+
+/// Renders the directory listing or file contents at `path` in the tree of
+/// the commit `rev` resolves to
+fn render_tree(
+    reponame: &str,
+    rev: &str,
+    path: &str,
+) -> Result<Html<String>, AppError> {
+    let mut result: Vec<String> = Vec::new();
+    let repo = open_repo(reponame)?;
+    let commit = resolve_commit(&repo, rev)?;
+    let path = path.trim_matches('/');
+
+    result.push(header().to_string());
+    result.push(repo_header(reponame, Some((rev, &commit))));
 
-    let repo =
-        Repository::open(std::path::Path::new(&config.dir).join(repo)).unwrap();
-    let head = repo.revparse_single("HEAD").unwrap();
-    let path = std::path::Path::new(&path).strip_prefix("/").unwrap();
-    let head_commit = head.into_commit().unwrap();
-    let head_tree = head_commit.tree().unwrap();
-    let obj = if !path.eq(std::path::Path::new("")) {
-        head_tree.get_path(path).unwrap().to_object(&repo).unwrap()
-    } else {
-        head_tree.as_object().to_owned()
+    let obj = find_path(&repo, &commit, path)?;
+    let revpath = urlencode(rev, "");
+    let pathurl = match path {
+        "" => String::new(),
+        _ => format!("/{}", urlencode(path, "/")),
     };
-    match obj.kind().unwrap() {
-        ObjectType::Tree => {
-            let tree = obj.into_tree().unwrap();
-            result.append(&mut write_files(&repo, &tree));
+    let logurl = format!("/{}/log/{}{}", reponame, revpath, pathurl);
+    match obj.kind() {
+        Some(ObjectType::Tree) => {
+            let tree = obj.peel_to_tree()?;
+            result.push(format!(
+                "<p>/{} <a href=\"{}\">History</a></p>",
+                xmlencode(path),
+                logurl
+            ));
+            let dirurl = format!("/{}/tree/{}{}", reponame, revpath, pathurl);
+            result.append(&mut write_files(&repo, &dirurl, &tree)?);
         }
-        ObjectType::Blob => {
-            let filename = basename(path.to_str().unwrap(), '/');
-            let blob = obj.into_blob().unwrap();
-            result.push(format!("<p>{} ({}B)</p>", filename, blob.size()));
+        Some(ObjectType::Blob) => {
+            let filename = basename(path, '/');
+            let blob = obj.peel_to_blob()?;
+            let fileurl = format!("{}{}", revpath, pathurl);
+            result.push(format!(
+                "<p>{} ({}B) <a href=\"{logurl}?follow=true\">History</a> \
+                 <a href=\"/{reponame}/blame/{fileurl}\">Blame</a> \
+                 <a href=\"/{reponame}/raw/{fileurl}\">Raw</a></p>",
+                xmlencode(filename),
+                blob.size(),
+            ));
             result.push("<hr>".to_string());
             if blob.is_binary() {
                 result.push("<p>Binary file.</p>".to_string());
             } else {
                 result.push(format!(
                     "<pre>{}</pre>",
-                    xmlencode(
-                        std::str::from_utf8(blob.content())
-                            .unwrap()
-                            .to_string()
-                            .as_ref()
-                    )
+                    xmlencode(&String::from_utf8_lossy(blob.content()))
                 ));
             }
         }
         _ => (),
     };
     result.push(footer().to_string());
-    Html(result.join(""))
+    Ok(Html(result.join("")))
 }
 
-fn header() -> String {
-    format!(
-        "<!DOCTYPE html><html> \
+/// Returns the repository name, description, owner, clone URLs and
+/// navigation links shown at the top of every repository page, along with
+/// the ref being browsed if any
+fn repo_header(repo: &str, current: Option<(&str, &Commit)>) -> String {
+    let config = Config::load();
+    let mut result = String::new();
+    result.push_str(&format!("<h1>{repo}</h1>"));
+    if let Ok(gitrepo) = open_repo(repo) {
+        let info = get_repoinfo(config, &gitrepo, repo);
+        if let Some(description) = &info.description {
+            result.push_str(&format!("<p>{}</p>", xmlencode(description)));
+        }
+        if let Some(owner) = &info.owner {
+            result.push_str(&format!("<p>Owner: {}</p>", xmlencode(owner)));
+        }
+        for url in &info.clone_urls {
+            result.push_str(&format!(
+                "<span>git clone {}</span>",
+                xmlencode(url)
+            ));
+        }
+    }
+    // Keep browsing the same ref when switching between log and tree
+    let revpath = match current {
+        Some((rev, _)) => format!("/{}", urlencode(rev, "")),
+        None => String::new(),
+    };
+    result.push_str(&format!(
+        "<span>
+    <a href=\"/{repo}/log{revpath}\">Log</a>
+    <a href=\"/{repo}/tree{revpath}\">Tree</a>
+    <a href=\"/{repo}/refs\">Refs</a>
+    <a href=\"/{repo}/atom.xml\">Atom</a>
+    <a href=\"/{repo}/tags.xml\">Atom (tags)</a>
+            </span>"
+    ));
+    if let Some((rev, commit)) = current {
+        let oid = commit.id().to_string();
+        result.push_str(&format!(
+            "<p>Ref: <b>{}</b> at <a href=\"/{}/commit/{}\">{}</a></p>",
+            xmlencode(rev),
+            repo,
+            oid,
+            &oid[..7]
+        ));
+    }
+    result.push_str("<hr/>");
+    result
+}
+
+pub fn header() -> String {
+    "<!DOCTYPE html><html> \
   <head> \
   <link rel=\"stylesheet\" type=\"text/css\" href=\"/static/style.css\" />
   <link rel=\"icon\" type=\"image/x-icon\" href=\"/static/favicon.ico\">
   </head> \
   <body>"
-    )
+        .to_string()
 }
 
-fn footer() -> &'static str {
+pub fn footer() -> &'static str {
     "</body></html>"
 }
 
 fn basename(path: &str, sep: char) -> &str {
     let mut pieces = path.rsplit(sep);
     match pieces.next() {
-        Some(p) => p.into(),
-        None => path.into(),
+        Some(p) => p,
+        None => path,
     }
 }
 
-fn write_files(repo: &Repository, tree: &Tree) -> Vec<String> {
+fn write_files(
+    repo: &Repository,
+    dirurl: &str,
+    tree: &Tree,
+) -> Result<Vec<String>> {
     let mut result = Vec::new();
     result.push("<table>".to_string());
     result.push(
@@ -125,20 +325,21 @@ fn write_files(repo: &Repository, tree: &Tree) -> Vec<String> {
             .to_string(),
     );
     for te in tree.iter() {
+        let name = te.name().unwrap_or("");
         result.push("<tr>".to_string());
         result.push(format!("<td>{:o}</td>", te.filemode()));
         result.push(format!(
-            "<td><a href={}/>{}</a></td>",
-            te.name().unwrap().to_string(),
-            te.name().unwrap().to_string(),
+            "<td><a href=\"{}/{}\">{}</a></td>",
+            dirurl,
+            urlencode(name, ""),
+            xmlencode(name),
         ));
-        let obj = te.to_object(repo).unwrap();
-        match obj.kind().unwrap() {
-            ObjectType::Blob => {
-                let blob = obj.into_blob().unwrap();
+        match te.kind() {
+            Some(ObjectType::Blob) => {
+                let blob = te.to_object(repo)?.peel_to_blob()?;
                 result.push(format!("<td>{}</td>", blob.size()));
             }
-            ObjectType::Tree => {
+            Some(ObjectType::Tree) => {
                 result.push(format!("<td>{}</td>", 0));
             }
             _ => (),
@@ -146,5 +347,5 @@ fn write_files(repo: &Repository, tree: &Tree) -> Vec<String> {
         result.push("</tr>".to_string());
     }
     result.push("</table>".to_string());
-    result
+    Ok(result)
 }
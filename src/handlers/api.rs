@@ -0,0 +1,408 @@
+use crate::config::Config;
+use crate::data::{
+    find_path, get_commitinfo, get_diffstat, get_repoinfo, open_repo,
+    resolve_commit, CommitHeader, DiffStat,
+};
+use crate::error::{ApiError, ClientError};
+use crate::extract::{Path, Query};
+use crate::handlers::list_repos;
+use crate::handlers::log::history;
+use crate::handlers::refs::get_refs;
+use crate::state::{AppState, Stats};
+use crate::util::print_time_z;
+use crate::worker::blocking;
+use anyhow::Result;
+use axum::Json;
+use git2::{Delta, DiffFlags, ObjectType, Signature, Time};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize)]
+pub struct Repo {
+    name: String,
+    description: Option<String>,
+    owner: Option<String>,
+    section: Option<String>,
+    clone_urls: Vec<String>,
+    last_change: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct Person {
+    name: String,
+    email: String,
+    date: String,
+}
+
+#[derive(Serialize)]
+pub struct CommitSummary {
+    oid: String,
+    parent: Option<String>,
+    author: Person,
+    committer: Person,
+    summary: Option<String>,
+    message: Option<String>,
+    files: usize,
+    additions: usize,
+    deletions: usize,
+}
+
+#[derive(Serialize)]
+pub struct CommitDetail {
+    #[serde(flatten)]
+    commit: CommitSummary,
+    diff: Vec<FileDiff>,
+}
+
+#[derive(Serialize)]
+pub struct FileDiff {
+    status: &'static str,
+    old_path: Option<String>,
+    new_path: Option<String>,
+    binary: bool,
+    additions: usize,
+    deletions: usize,
+    hunks: Vec<Hunk>,
+}
+
+#[derive(Serialize)]
+pub struct Hunk {
+    header: String,
+    lines: Vec<Line>,
+}
+
+#[derive(Serialize)]
+pub struct Line {
+    origin: char,
+    old_lineno: Option<u32>,
+    new_lineno: Option<u32>,
+    content: String,
+}
+
+#[derive(Serialize)]
+pub struct LogPage {
+    offset: usize,
+    limit: usize,
+    more: bool,
+    commits: Vec<CommitSummary>,
+}
+
+#[derive(Serialize)]
+pub struct Refs {
+    branches: Vec<Ref>,
+    tags: Vec<Ref>,
+}
+
+#[derive(Serialize)]
+pub struct Ref {
+    name: String,
+    oid: String,
+    author: Person,
+    summary: Option<String>,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum TreeObject {
+    Tree {
+        oid: String,
+        path: String,
+        entries: Vec<TreeEntry>,
+    },
+    Blob {
+        oid: String,
+        path: String,
+        size: usize,
+        binary: bool,
+    },
+}
+
+#[derive(Serialize)]
+pub struct TreeEntry {
+    name: String,
+    mode: String,
+    #[serde(rename = "type")]
+    kind: Option<&'static str>,
+    oid: String,
+    size: Option<usize>,
+}
+
+#[derive(Deserialize)]
+pub struct LogQuery {
+    offset: Option<usize>,
+    limit: Option<usize>,
+    follow: Option<bool>,
+}
+
+/// Answers requests under the API prefix matching no route
+pub async fn not_found() -> ApiError {
+    ClientError::PageNotFound.into()
+}
+
+/// Hit and miss counters of the repository pool and the diffstat cache
+pub async fn stats() -> Json<Stats> {
+    Json(AppState::load().stats())
+}
+
+pub async fn repos() -> Result<Json<Vec<Repo>>, ApiError> {
+    blocking(repo_list).await
+}
+
+fn repo_list() -> Result<Json<Vec<Repo>>, ApiError> {
+    let config = Config::load();
+    let mut repos = Vec::new();
+    for name in list_repos(&config.dir) {
+        // Skipped like on the index page, rather than failing the whole list
+        let Ok(repo) = open_repo(&name) else {
+            continue;
+        };
+        let info = get_repoinfo(config, &repo, &name);
+        repos.push(Repo {
+            description: info.description,
+            owner: info.owner,
+            section: info.section,
+            clone_urls: info.clone_urls,
+            last_change: info.last_change.map(time_z).transpose()?,
+            name,
+        });
+    }
+    Ok(Json(repos))
+}
+
+pub async fn log_head(
+    Path(repo): Path<String>,
+    Query(query): Query<LogQuery>,
+) -> Result<Json<LogPage>, ApiError> {
+    blocking(move || {
+        log_page(&repo, Config::load().default_rev(&repo), "", &query)
+    })
+    .await
+}
+
+pub async fn log(
+    Path((repo, rev)): Path<(String, String)>,
+    Query(query): Query<LogQuery>,
+) -> Result<Json<LogPage>, ApiError> {
+    blocking(move || log_page(&repo, &rev, "", &query)).await
+}
+
+pub async fn log_path(
+    Path((repo, rev, path)): Path<(String, String, String)>,
+    Query(query): Query<LogQuery>,
+) -> Result<Json<LogPage>, ApiError> {
+    blocking(move || log_page(&repo, &rev, &path, &query)).await
+}
+
+/// Returns one page of the history reachable from the commit `rev` resolves
+/// to, limited to the commits touching `path` unless it is empty, like the
+/// log pages
+fn log_page(
+    reponame: &str,
+    rev: &str,
+    path: &str,
+    query: &LogQuery,
+) -> Result<Json<LogPage>, ApiError> {
+    let config = Config::load();
+    let repo = open_repo(reponame)?;
+    let commit = resolve_commit(&repo, rev)?;
+    let offset = query.offset.unwrap_or(0);
+    let limit = query
+        .limit
+        .unwrap_or(config.log_page_size)
+        .clamp(1, config.log_max_page_size);
+    let follow = query.follow.unwrap_or(false);
+    let path = path.trim_matches('/');
+
+    let ids = history(&repo, reponame, &commit, path, follow)?;
+    let mut ids = ids.skip(offset);
+    let mut commits = Vec::new();
+    for id in ids.by_ref().take(limit) {
+        let commit = repo.find_commit(id?)?;
+        let stat = get_diffstat(&repo, &commit)?;
+        let header = CommitHeader::new(&commit);
+        commits.push(CommitSummary::new(&header, &stat)?);
+    }
+    Ok(Json(LogPage {
+        offset,
+        limit,
+        more: ids.next().is_some(),
+        commits,
+    }))
+}
+
+pub async fn commit(
+    Path((repo, hash)): Path<(String, String)>,
+) -> Result<Json<CommitDetail>, ApiError> {
+    blocking(move || commit_detail(&repo, hash)).await
+}
+
+fn commit_detail(
+    reponame: &str,
+    hash: String,
+) -> Result<Json<CommitDetail>, ApiError> {
+    let repo = open_repo(reponame)?;
+    let ci = get_commitinfo(&repo, hash)?;
+
+    let mut diff = Vec::new();
+    for di in &ci.deltas {
+        let patch = &di.patch;
+        let delta = patch.delta();
+        let mut hunks = Vec::new();
+        for i in 0..patch.num_hunks() {
+            let (hunk, count) = patch.hunk(i)?;
+            let mut lines = Vec::new();
+            for j in 0..count {
+                let line = patch.line_in_hunk(i, j)?;
+                lines.push(Line {
+                    origin: line.origin(),
+                    old_lineno: line.old_lineno(),
+                    new_lineno: line.new_lineno(),
+                    content: String::from_utf8_lossy(line.content()).into(),
+                });
+            }
+            hunks.push(Hunk {
+                header: String::from_utf8_lossy(hunk.header()).into(),
+                lines,
+            });
+        }
+        diff.push(FileDiff {
+            status: delta_status(delta.status()),
+            old_path: delta.old_file().path().map(|p| p.display().to_string()),
+            new_path: delta.new_file().path().map(|p| p.display().to_string()),
+            binary: delta.flags().contains(DiffFlags::BINARY),
+            additions: di.add_count,
+            deletions: di.del_count,
+            hunks,
+        });
+    }
+    Ok(Json(CommitDetail {
+        commit: CommitSummary::new(&ci.header, &ci.diffstat())?,
+        diff,
+    }))
+}
+
+pub async fn refs(Path(repo): Path<String>) -> Result<Json<Refs>, ApiError> {
+    blocking(move || ref_list(&repo)).await
+}
+
+fn ref_list(reponame: &str) -> Result<Json<Refs>, ApiError> {
+    let repo = open_repo(reponame)?;
+    let mut branches = Vec::new();
+    let mut tags = Vec::new();
+    for ri in get_refs(&repo)? {
+        let r = Ref {
+            name: ri.rf.shorthand().unwrap_or("").to_string(),
+            oid: ri.commit.oid.clone(),
+            author: Person::from_signature(&ri.commit.author)?,
+            summary: ri.commit.summary.clone(),
+        };
+        match ri.rf.is_tag() {
+            true => tags.push(r),
+            false => branches.push(r),
+        }
+    }
+    Ok(Json(Refs { branches, tags }))
+}
+
+pub async fn tree_root(
+    Path((repo, rev)): Path<(String, String)>,
+) -> Result<Json<TreeObject>, ApiError> {
+    blocking(move || tree_object(&repo, &rev, "")).await
+}
+
+pub async fn tree(
+    Path((repo, rev, path)): Path<(String, String, String)>,
+) -> Result<Json<TreeObject>, ApiError> {
+    blocking(move || tree_object(&repo, &rev, &path)).await
+}
+
+fn tree_object(
+    reponame: &str,
+    rev: &str,
+    path: &str,
+) -> Result<Json<TreeObject>, ApiError> {
+    let repo = open_repo(reponame)?;
+    let commit = resolve_commit(&repo, rev)?;
+    let path = path.trim_matches('/');
+    let obj = find_path(&repo, &commit, path)?;
+
+    let object = match obj.kind() {
+        Some(ObjectType::Blob) => {
+            let blob = obj.peel_to_blob()?;
+            TreeObject::Blob {
+                oid: blob.id().to_string(),
+                path: path.to_string(),
+                size: blob.size(),
+                binary: blob.is_binary(),
+            }
+        }
+        _ => {
+            let tree = obj.peel_to_tree()?;
+            let mut entries = Vec::new();
+            for te in tree.iter() {
+                let size = match te.kind() {
+                    Some(ObjectType::Blob) => {
+                        Some(te.to_object(&repo)?.peel_to_blob()?.size())
+                    }
+                    _ => None,
+                };
+                entries.push(TreeEntry {
+                    name: te.name().unwrap_or("").to_string(),
+                    mode: format!("{:o}", te.filemode()),
+                    kind: te.kind().map(|k| k.str()),
+                    oid: te.id().to_string(),
+                    size,
+                });
+            }
+            TreeObject::Tree {
+                oid: tree.id().to_string(),
+                path: path.to_string(),
+                entries,
+            }
+        }
+    };
+    Ok(Json(object))
+}
+
+impl CommitSummary {
+    fn new(header: &CommitHeader, stat: &DiffStat) -> Result<Self> {
+        Ok(CommitSummary {
+            oid: header.oid.clone(),
+            parent: header.parentoid.clone(),
+            author: Person::from_signature(&header.author)?,
+            committer: Person::from_signature(&header.committer)?,
+            summary: header.summary.clone(),
+            message: header.msg.clone(),
+            files: stat.files,
+            additions: stat.additions,
+            deletions: stat.deletions,
+        })
+    }
+}
+
+impl Person {
+    fn from_signature(sig: &Signature) -> Result<Self> {
+        Ok(Person {
+            name: sig.name().unwrap_or("").to_string(),
+            email: sig.email().unwrap_or("").to_string(),
+            date: time_z(sig.when())?,
+        })
+    }
+}
+
+fn time_z(time: Time) -> Result<String> {
+    let mut date = String::new();
+    print_time_z(&mut date, time)?;
+    Ok(date)
+}
+
+fn delta_status(status: Delta) -> &'static str {
+    match status {
+        Delta::Added => "added",
+        Delta::Copied => "copied",
+        Delta::Deleted => "deleted",
+        Delta::Modified => "modified",
+        Delta::Renamed => "renamed",
+        Delta::Typechange => "typechange",
+        _ => "unmodified",
+    }
+}
@@ -1,6 +1,6 @@
+use crate::extract::Path;
 use axum::{
-    body::{self, Empty, Full},
-    extract::Path,
+    body::Body,
     http::StatusCode,
     response::{IntoResponse, Response},
 };
@@ -13,11 +13,11 @@ pub async fn asset(Path(path): Path<String>) -> impl IntoResponse {
     match STATIC_DIR.get_file(path) {
         None => Response::builder()
             .status(StatusCode::NOT_FOUND)
-            .body(body::boxed(Empty::new()))
+            .body(Body::empty())
             .unwrap(),
         Some(f) => Response::builder()
             .status(StatusCode::OK)
-            .body(body::boxed(Full::from(f.contents())))
+            .body(Body::from(f.contents()))
             .unwrap(),
     }
 }
@@ -0,0 +1,145 @@
+use crate::data::{find_path, open_repo, rename_source, resolve_commit};
+use crate::error::{AppError, ClientError};
+use crate::extract::Path;
+use crate::handlers::{footer, header, repo_header};
+use crate::util::{print_time_short, urlencode, xmlencode, xmlencodeline};
+use crate::worker::blocking;
+use anyhow::Result;
+use axum::response::Html;
+use git2::{Blame, BlameOptions, Oid, Repository};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::path::PathBuf;
+
+pub async fn blame(
+    Path((repo, rev, path)): Path<(String, String, String)>,
+) -> Result<Html<String>, AppError> {
+    blocking(move || render_blame(&repo, &rev, &path)).await
+}
+
+fn render_blame(
+    reponame: &str,
+    rev: &str,
+    path: &str,
+) -> Result<Html<String>, AppError> {
+    let mut result: Vec<String> = Vec::new();
+    let repo = open_repo(reponame)?;
+    let commit = resolve_commit(&repo, rev)?;
+    let path = std::path::Path::new(path.trim_matches('/'));
+
+    result.push(header().to_string());
+    result.push(repo_header(reponame, Some((rev, &commit))));
+
+    let blob = find_path(&repo, &commit, path.to_str().unwrap_or(""))?
+        .into_blob()
+        .map_err(|_| ClientError::PathNotFound(path.display().to_string()))?;
+    result.push(format!(
+        "<p>{} ({}B) <a href=\"/{}/tree/{}/{}\">View</a></p>",
+        xmlencode(path.display().to_string().as_ref()),
+        blob.size(),
+        reponame,
+        urlencode(rev, ""),
+        urlencode(path.to_str().unwrap_or(""), "/"),
+    ));
+    result.push("<hr/>".to_string());
+    if blob.is_binary() {
+        result.push("<p>Binary file.</p>".to_string());
+    } else {
+        let blame = repo.blame_file(
+            path,
+            Some(BlameOptions::new().newest_commit(commit.id())),
+        )?;
+        let mut buf = String::new();
+        print_blame(&mut buf, reponame, &repo, &blame, blob.content())?;
+        result.push(buf);
+    }
+    result.push(footer().to_string());
+    Ok(Html(result.join("")))
+}
+
+/// Writes one table row per line of `content`, with the commit, author and
+/// date of the hunk it belongs to on the first line of every hunk
+fn print_blame<W: Write>(
+    w: &mut W,
+    relpath: &str,
+    repo: &Repository,
+    blame: &Blame,
+    content: &[u8],
+) -> Result<()> {
+    let lines = content.split_inclusive(|&b| b == b'\n').collect::<Vec<_>>();
+    // Paths of the file in the parent of every commit, as hunks of the same
+    // commit are many
+    let mut parent_paths = HashMap::new();
+    write!(w, "<table id=\"blame\">\n")?;
+    for hunk in blame.iter() {
+        let oid = hunk.final_commit_id();
+        let start = hunk.final_start_line();
+        let sig = hunk.final_signature();
+        for lineno in start..start + hunk.lines_in_hunk() {
+            write!(w, "<tr>")?;
+            if lineno == start {
+                write!(w, "<td class=\"h\">")?;
+                write!(
+                    w,
+                    "<a href=\"/{}/commit/{}\">{:.7}</a>",
+                    relpath, oid, oid
+                )?;
+                write!(w, "</td><td class=\"h\">")?;
+                write!(w, "{}", xmlencode(sig.name().unwrap_or("")))?;
+                write!(w, "</td><td class=\"h\">")?;
+                print_time_short(w, sig.when())?;
+                write!(w, "</td><td class=\"h\">")?;
+                // Blame the same lines as they were before this change
+                let parent_path = match hunk.path() {
+                    Some(path) => parent_paths
+                        .entry(oid)
+                        .or_insert_with(|| parent_path(repo, oid, path))
+                        .as_ref(),
+                    None => None,
+                };
+                if let Some(path) = parent_path {
+                    write!(
+                        w,
+                        "<a href=\"/{}/blame/{}%5E/{}#l{}\" title=\"blame \
+                         prior to this change\">^</a>",
+                        relpath,
+                        oid,
+                        urlencode(path.to_str().unwrap_or(""), "/"),
+                        hunk.orig_start_line()
+                    )?;
+                }
+                write!(w, "</td>")?;
+            } else {
+                write!(w, "<td></td><td></td><td></td><td></td>")?;
+            }
+            write!(
+                w,
+                "<td class=\"num\"><a href=\"#l{}\" id=\"l{}\">{}</a></td>",
+                lineno, lineno, lineno
+            )?;
+            let line = lines
+                .get(lineno - 1)
+                .map(|l| String::from_utf8_lossy(l))
+                .unwrap_or_default();
+            write!(w, "<td><pre>{}</pre></td>", xmlencodeline(&line))?;
+            write!(w, "</tr>\n")?;
+        }
+    }
+    write!(w, "</table>")?;
+    Ok(())
+}
+
+/// Returns the path in the first parent of the commit `oid` of the file at
+/// `path` in it, following a rename, or `None` if the file is not there
+fn parent_path(
+    repo: &Repository,
+    oid: Oid,
+    path: &std::path::Path,
+) -> Option<PathBuf> {
+    let commit = repo.find_commit(oid).ok()?;
+    let parent_tree = commit.parent(0).ok()?.tree().ok()?;
+    if parent_tree.get_path(path).is_ok() {
+        return Some(path.to_path_buf());
+    }
+    rename_source(repo, &parent_tree, &commit.tree().ok()?, path).ok()?
+}
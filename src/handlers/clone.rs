@@ -0,0 +1,292 @@
+use crate::data::open_repo;
+use crate::error::AppError;
+use crate::extract::{Path, Query};
+use crate::listen::ACTIVATION_VARS;
+use crate::worker::blocking;
+use anyhow::{anyhow, Result};
+use axum::{
+    body::{Body, Bytes},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use flate2::read::GzDecoder;
+use git2::{ObjectType, ReferenceType, Repository};
+use serde::Deserialize;
+use std::io::Read;
+use std::path::PathBuf;
+use std::process::Stdio;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tokio_util::io::ReaderStream;
+
+const UPLOAD_PACK: &str = "git-upload-pack";
+
+#[derive(Deserialize)]
+pub struct InfoRefsQuery {
+    service: Option<String>,
+}
+
+/// Serves the ref advertisement that starts a smart HTTP clone or fetch, or
+/// the `info/refs` file of the dumb protocol when no service is asked for
+pub async fn info_refs(
+    Path(repo): Path<String>,
+    Query(query): Query<InfoRefsQuery>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let gitdir = git_dir(&repo).await?;
+    match query.service.as_deref() {
+        Some(UPLOAD_PACK) => (),
+        Some(_) => {
+            return Ok(
+                (StatusCode::FORBIDDEN, "Service not enabled").into_response()
+            )
+        }
+        None => return blocking(move || dumb_info_refs(&gitdir)).await,
+    }
+
+    let protocol = git_protocol(&headers);
+    let output = upload_pack(&gitdir, protocol.as_deref())
+        .arg("--advertise-refs")
+        .output()
+        .await?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "git-upload-pack failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        )
+        .into());
+    }
+
+    // Protocol v2 clients expect the capability advertisement right away,
+    // older ones need the service announcement first
+    let mut body = Vec::new();
+    if !protocol.as_deref().is_some_and(|p| p.contains("version=2")) {
+        body.extend(pkt_line(&format!("# service={}\n", UPLOAD_PACK)));
+        body.extend(b"0000");
+    }
+    body.extend(output.stdout);
+
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .header(
+            header::CONTENT_TYPE,
+            format!("application/x-{}-advertisement", UPLOAD_PACK),
+        )
+        .header(header::CACHE_CONTROL, "no-cache")
+        .body(Body::from(body))?;
+    Ok(response)
+}
+
+/// Answers the negotiation request of a smart HTTP clone or fetch by piping
+/// it through `git upload-pack` and streaming back the resulting pack
+pub async fn git_upload_pack(
+    Path(repo): Path<String>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response, AppError> {
+    let gitdir = git_dir(&repo).await?;
+    let input = match headers.get(header::CONTENT_ENCODING) {
+        Some(encoding) if encoding == "gzip" => {
+            let mut input = Vec::new();
+            GzDecoder::new(&body[..]).read_to_end(&mut input)?;
+            input
+        }
+        _ => body.to_vec(),
+    };
+
+    let mut child = upload_pack(&gitdir, git_protocol(&headers).as_deref())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()?;
+    let mut stdin = child.stdin.take().ok_or(anyhow!("No stdin"))?;
+    let stdout = child.stdout.take().ok_or(anyhow!("No stdout"))?;
+    tokio::spawn(async move {
+        // Closing stdin once the request is written lets upload-pack answer
+        let _ = stdin.write_all(&input).await;
+        drop(stdin);
+        let _ = child.wait().await;
+    });
+
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .header(
+            header::CONTENT_TYPE,
+            format!("application/x-{}-result", UPLOAD_PACK),
+        )
+        .header(header::CACHE_CONTROL, "no-cache")
+        .body(Body::from_stream(ReaderStream::new(stdout)))?;
+    Ok(response)
+}
+
+/// Generates `info/refs` the way `git update-server-info` would, so that
+/// repositories where it never ran can still be cloned
+fn dumb_info_refs(gitdir: &std::path::Path) -> Result<Response, AppError> {
+    let repo = Repository::open(gitdir)?;
+    let mut refs = repo
+        .references()?
+        .filter_map(|rf| rf.ok())
+        .filter(|rf| rf.kind() == Some(ReferenceType::Direct))
+        .collect::<Vec<_>>();
+    refs.sort_by(|a, b| a.name_bytes().cmp(b.name_bytes()));
+
+    let mut body = String::new();
+    for rf in refs {
+        let (Some(name), Some(oid)) = (rf.name(), rf.target()) else {
+            continue;
+        };
+        body.push_str(&format!("{}\t{}\n", oid, name));
+        // Annotated tags are followed by the object they point to
+        if let Ok(tag) = repo.find_tag(oid) {
+            let peeled = tag.into_object().peel(ObjectType::Any)?;
+            body.push_str(&format!("{}\t{}^{{}}\n", peeled.id(), name));
+        }
+    }
+    Ok(text_response(body))
+}
+
+/// Serves `HEAD` for the dumb protocol
+pub async fn head(Path(repo): Path<String>) -> Result<Response, AppError> {
+    let gitdir = git_dir(&repo).await?;
+    blocking(move || head_file(&gitdir)).await
+}
+
+fn head_file(gitdir: &std::path::Path) -> Result<Response, AppError> {
+    let repo = Repository::open(gitdir)?;
+    let head = repo.find_reference("HEAD")?;
+    let body = match (head.symbolic_target(), head.target()) {
+        (Some(target), _) => format!("ref: {}\n", target),
+        (None, Some(oid)) => format!("{}\n", oid),
+        _ => return Err(anyhow!("Invalid HEAD").into()),
+    };
+    Ok(text_response(body))
+}
+
+/// Serves `objects/info/packs` for the dumb protocol, listing the packfiles
+/// currently in the repository
+pub async fn info_packs(
+    Path(repo): Path<String>,
+) -> Result<Response, AppError> {
+    let packdir = git_dir(&repo).await?.join("objects").join("pack");
+    let mut packs = std::fs::read_dir(packdir)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter(|name| is_pack_file(name) && name.ends_with(".pack"))
+        .collect::<Vec<String>>();
+    packs.sort();
+    let mut body = String::new();
+    for pack in packs {
+        body.push_str(&format!("P {}\n", pack));
+    }
+    body.push('\n');
+    Ok(text_response(body))
+}
+
+/// Serves a loose object for the dumb protocol
+pub async fn loose_object(
+    Path((repo, dir, file)): Path<(String, String, String)>,
+) -> Result<Response, AppError> {
+    let is_hex = |s: &str| s.bytes().all(|b| b.is_ascii_hexdigit());
+    if dir.len() != 2 || !is_hex(&dir) || file.len() < 38 || !is_hex(&file) {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    }
+    let path = git_dir(&repo).await?.join("objects").join(dir).join(file);
+    serve_file(&path, "application/x-git-loose-object").await
+}
+
+/// Serves a packfile or its index for the dumb protocol
+pub async fn pack_file(
+    Path((repo, file)): Path<(String, String)>,
+) -> Result<Response, AppError> {
+    let content_type = match file.rsplit_once('.') {
+        Some((_, "pack")) => "application/x-git-packed-objects",
+        Some((_, "idx")) => "application/x-git-packed-objects-toc",
+        _ => return Ok(StatusCode::NOT_FOUND.into_response()),
+    };
+    if !is_pack_file(&file) {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    }
+    let path = git_dir(&repo)
+        .await?
+        .join("objects")
+        .join("pack")
+        .join(file);
+    serve_file(&path, content_type).await
+}
+
+/// Returns whether `name` looks like `pack-<hex>.pack` or `pack-<hex>.idx`
+fn is_pack_file(name: &str) -> bool {
+    let Some(rest) = name.strip_prefix("pack-") else {
+        return false;
+    };
+    let hash = rest
+        .strip_suffix(".pack")
+        .or_else(|| rest.strip_suffix(".idx"))
+        .unwrap_or("");
+    !hash.is_empty() && hash.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Streams the file at `path`, or answers 404 if there is none
+async fn serve_file(
+    path: &std::path::Path,
+    content_type: &str,
+) -> Result<Response, AppError> {
+    let Ok(file) = tokio::fs::File::open(path).await else {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    };
+    let len = file.metadata().await?.len();
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, content_type)
+        .header(header::CONTENT_LENGTH, len)
+        .body(Body::from_stream(ReaderStream::new(file)))?;
+    Ok(response)
+}
+
+fn text_response(body: String) -> Response {
+    (
+        [
+            (header::CONTENT_TYPE, "text/plain; charset=utf-8"),
+            (header::CACHE_CONTROL, "no-cache"),
+        ],
+        body,
+    )
+        .into_response()
+}
+
+/// Returns the git directory of the repository named `repo`
+async fn git_dir(repo: &str) -> Result<PathBuf> {
+    let repo = repo.to_string();
+    blocking(move || Ok(open_repo(&repo)?.path().to_path_buf())).await
+}
+
+/// Returns the protocol parameters the client sent in `Git-Protocol`
+fn git_protocol(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get("Git-Protocol")
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_string())
+}
+
+/// Prepares a stateless `git upload-pack` run as used by git-http-backend
+fn upload_pack(gitdir: &std::path::Path, protocol: Option<&str>) -> Command {
+    let mut cmd = Command::new("git");
+    cmd.arg("upload-pack")
+        .arg("--stateless-rpc")
+        .arg(gitdir)
+        .kill_on_drop(true);
+    for var in ACTIVATION_VARS {
+        cmd.env_remove(var);
+    }
+    if let Some(protocol) = protocol {
+        cmd.env("GIT_PROTOCOL", protocol);
+    }
+    cmd
+}
+
+/// Encodes `data` as a pkt-line, prefixed by its length in four hex digits
+fn pkt_line(data: &str) -> Vec<u8> {
+    format!("{:04x}{}", data.len() + 4, data).into_bytes()
+}
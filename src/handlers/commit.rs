@@ -1,38 +1,39 @@
-use crate::config::Config;
-use crate::data::{get_commitinfo, CommitInfo};
+use crate::data::{get_commitinfo, open_repo, CommitInfo};
 use crate::error::AppError;
-use crate::handlers::{footer, header};
-use crate::util::{print_time, xmlencode, xmlencodeline};
-use anyhow::{anyhow, Result};
-use axum::{extract::Path, response::Html};
-use git2::{Delta, DiffFlags, Patch, Repository};
+use crate::extract::Path;
+use crate::handlers::{footer, header, repo_header};
+use crate::util::{
+    delta_letter, print_diffstat_summary, print_time, urlencode, xmlencode,
+    xmlencodeline,
+};
+use crate::worker::blocking;
+use anyhow::Result;
+use axum::response::Html;
+use git2::{Delta, DiffFlags};
 use std::fmt::Write;
 
 pub async fn commit(
     Path((repo, hash)): Path<(String, String)>,
+) -> Result<Html<String>, AppError> {
+    blocking(move || render_commit(&repo, hash)).await
+}
+
+fn render_commit(
+    reponame: &str,
+    hash: String,
 ) -> Result<Html<String>, AppError> {
     let mut result: Vec<String> = Vec::new();
-    let config = Config::load();
+    let baseurl = reponame.to_string();
     result.push(header().to_string());
-    result.push(format!("<h1>{repo}</h1>"));
-    result.push(format!("<span>git clone git://{repo}.git</span>"));
-    result.push(format!(
-        "<span>
-    <a href=\"/{repo}/log\">Log</a>
-    <a href=\"/{repo}/tree\">Tree</a>
-    <a href=\"/{repo}/refs\">Refs</a>
-            </span>"
-    ));
-    result.push("<hr/>".to_string());
+    result.push(repo_header(reponame, None));
 
-    let repo =
-        Repository::open(std::path::Path::new(&config.dir).join(repo)).unwrap();
+    let repo = open_repo(reponame)?;
     result.push("<pre>".to_string());
     let mut buf = String::new();
     let ci = &get_commitinfo(&repo, hash)?;
     print_commit(&mut buf, ci)?;
     print_diffstat(&mut buf, ci)?;
-    print_diff(&mut buf, ci)?;
+    print_diff(&mut buf, &baseurl, ci)?;
     result.push(buf);
     result.push("</pre>".to_string());
     result.push(footer().to_string());
@@ -41,22 +42,26 @@ pub async fn commit(
 
 fn print_commit<W: Write>(w: &mut W, ci: &CommitInfo) -> Result<()> {
     write!(w, "<b>commit</b> ")?;
-    write!(w, "<a href=\"../commit/{}\">{}</a>\n", ci.oid, ci.oid)?;
-    if let Some(poid) = &ci.parentoid {
+    write!(
+        w,
+        "<a href=\"../commit/{}\">{}</a>\n",
+        ci.header.oid, ci.header.oid
+    )?;
+    if let Some(poid) = &ci.header.parentoid {
         write!(w, "<b>parent</b> ")?;
         write!(w, "<a href=\"../commit/{}\">{}</a>\n", poid, poid)?;
     }
     write!(w, "<b>Author:</b> ")?;
-    write!(w, "{}", xmlencode(ci.author.name().unwrap_or("")))?;
-    let email = xmlencode(ci.author.email().unwrap_or(""));
+    write!(w, "{}", xmlencode(ci.header.author.name().unwrap_or("")))?;
+    let email = xmlencode(ci.header.author.email().unwrap_or(""));
     write!(w, " <<a href=\"mailto:{}]\">{}</a>>\n", email, email)?;
     write!(w, "<b>Date:</b>   ")?;
-    print_time(w, ci.author.when())?;
+    print_time(w, ci.header.author.when())?;
     write!(w, "\n")?;
-    if let Some(msg) = &ci.msg {
+    if let Some(msg) = &ci.header.msg {
         write!(w, "\n{}\n", xmlencode(msg))?;
     }
-    return Ok(());
+    Ok(())
 }
 
 fn print_diffstat<W: Write>(w: &mut W, ci: &CommitInfo) -> Result<()> {
@@ -65,15 +70,7 @@ fn print_diffstat<W: Write>(w: &mut W, ci: &CommitInfo) -> Result<()> {
     const TOTAL: usize = 80;
 
     for (i, delta) in ci.diff.deltas().enumerate() {
-        let c = match delta.status() {
-            Delta::Added => 'A',
-            Delta::Copied => 'C',
-            Delta::Deleted => 'D',
-            Delta::Modified => 'M',
-            Delta::Renamed => 'R',
-            Delta::Typechange => 'T',
-            _ => ' ',
-        };
+        let c = delta_letter(delta.status());
         if c == ' ' {
             write!(w, "<tr><td>{}", c)?;
         } else {
@@ -129,64 +126,54 @@ fn print_diffstat<W: Write>(w: &mut W, ci: &CommitInfo) -> Result<()> {
         write!(w, "</span></td></tr>\n")?;
     }
     write!(w, "</table></pre>")?;
-    write!(
-        w,
-        "<pre>{} file{} changed, {} insertion{}(+), {} deletion{}(-)\n",
-        ci.deltas.len(),
-        match ci.deltas.len() {
-            1 => "",
-            _ => "s",
-        },
-        ci.add_count,
-        match ci.add_count {
-            1 => "",
-            _ => "s",
-        },
-        ci.del_count,
-        match ci.del_count {
-            1 => "",
-            _ => "s",
-        },
-    )?;
+    write!(w, "<pre>")?;
+    print_diffstat_summary(w, ci.deltas.len(), ci.add_count, ci.del_count)?;
+    write!(w, "\n")?;
     write!(w, "<hr/>")?;
     Ok(())
 }
 
-fn print_diff<W: Write>(w: &mut W, ci: &CommitInfo) -> Result<()> {
-    let diff = &ci.diff;
-    for i in 0..diff.deltas().len() {
-        let patch = Patch::from_diff(&diff, i)?
-            .ok_or(anyhow!("Error getting patch"))?;
+fn print_diff<W: Write>(
+    w: &mut W,
+    relpath: &str,
+    ci: &CommitInfo,
+) -> Result<()> {
+    for (i, di) in ci.deltas.iter().enumerate() {
+        let patch = &di.patch;
         let delta = patch.delta();
 
-        let old_file = xmlencode(
-            delta
-                .old_file()
-                .path()
-                .unwrap_or(std::path::Path::new(""))
-                .display()
-                .to_string()
-                .as_ref(),
-        );
-        let new_file = xmlencode(
-            delta
-                .new_file()
-                .path()
-                .unwrap_or(std::path::Path::new(""))
-                .display()
-                .to_string()
-                .as_ref(),
-        );
-        write!(
-            w,
-            "<b>diff --git a/<a id=\"h{}\" href=\"../tree/{}\">{}</a>",
-            i, old_file, old_file,
-        )?;
-        write!(
-            w,
-            " b/<a href=\"../tree/{}\">{}</a></b>\n",
-            new_file, new_file
-        )?;
+        let old_path =
+            delta.old_file().path().unwrap_or(std::path::Path::new(""));
+        let new_path =
+            delta.new_file().path().unwrap_or(std::path::Path::new(""));
+        let old_file = xmlencode(old_path.display().to_string().as_ref());
+        let new_file = xmlencode(new_path.display().to_string().as_ref());
+        // Link each side of the diff to the tree it exists in, which is the
+        // parent for the old file and the commit itself for the new one
+        write!(w, "<b>diff --git a/<a id=\"h{}\"", i)?;
+        match &ci.header.parentoid {
+            Some(poid) if delta.status() != Delta::Added => write!(
+                w,
+                " href=\"/{}/tree/{}/{}\"",
+                relpath,
+                poid,
+                urlencode(old_path.to_str().unwrap_or(""), "/"),
+            )?,
+            _ => (),
+        }
+        write!(w, ">{}</a>", old_file)?;
+        if delta.status() == Delta::Deleted {
+            write!(w, " b/{}</b>\n", new_file)?;
+        } else {
+            write!(
+                w,
+                " b/<a href=\"/{}/tree/{}/{}\">{}</a></b>\n",
+                relpath,
+                ci.header.oid,
+                urlencode(new_path.to_str().unwrap_or(""), "/"),
+                new_file
+            )?;
+        }
 
         if delta.flags().contains(DiffFlags::BINARY) {
             write!(w, "Binary files differ\n")?;
@@ -204,15 +191,12 @@ fn print_diff<W: Write>(w: &mut W, ci: &CommitInfo) -> Result<()> {
             write!(
                 w,
                 "{}",
-                xmlencode(String::from_utf8(hunk.header().to_vec())?.as_ref())
+                xmlencode(&String::from_utf8_lossy(hunk.header()))
             )?;
             write!(w, "</a>")?;
 
             let mut k = 0;
-            loop {
-                let Ok(line) = patch.line_in_hunk(j, k) else {
-                    break;
-                };
+            while let Ok(line) = patch.line_in_hunk(j, k) {
                 if line.old_lineno().is_none() {
                     write!(
                         w,
@@ -231,9 +215,7 @@ fn print_diff<W: Write>(w: &mut W, ci: &CommitInfo) -> Result<()> {
                 write!(
                     w,
                     "{}",
-                    xmlencodeline(
-                        String::from_utf8(line.content().to_vec())?.as_ref()
-                    )
+                    xmlencodeline(&String::from_utf8_lossy(line.content()))
                 )?;
                 write!(w, "\n")?;
                 if line.old_lineno().is_none() || line.new_lineno().is_none() {
@@ -0,0 +1,224 @@
+use crate::config::Config;
+use crate::data::{get_commitinfo, open_repo, resolve_commit, CommitInfo};
+use crate::error::AppError;
+use crate::extract::{Path, Query};
+use crate::util::{
+    delta_letter, print_diffstat_summary, print_time_z, xmlencode,
+};
+use crate::worker::blocking;
+use anyhow::Result;
+use axum::{
+    http::header,
+    response::{IntoResponse, Response},
+};
+use git2::Signature;
+use serde::Deserialize;
+use std::fmt::Write;
+
+#[derive(Deserialize)]
+pub struct FeedQuery {
+    #[serde(rename = "ref")]
+    rev: Option<String>,
+}
+
+/// Atom feed of the latest commits on the default branch, or on `?ref=` if
+/// given
+pub async fn atom(
+    Path(repo): Path<String>,
+    Query(query): Query<FeedQuery>,
+) -> Result<Response, AppError> {
+    blocking(move || commit_feed(&repo, query.rev.as_deref())).await
+}
+
+fn commit_feed(
+    reponame: &str,
+    rev: Option<&str>,
+) -> Result<Response, AppError> {
+    let config = Config::load();
+    let repo = open_repo(reponame)?;
+    let rev = rev.unwrap_or(config.default_rev(reponame));
+    let commit = resolve_commit(&repo, rev)?;
+
+    let mut w = String::new();
+    print_feed_header(&mut w, &format!("{}, {}", reponame, rev))?;
+    let mut revwalk = repo.revwalk()?;
+    revwalk.push(commit.id())?;
+    for id in revwalk.take(config.feed_size) {
+        let ci = get_commitinfo(&repo, id?.to_string())?;
+        print_commit_entry(&mut w, reponame, &ci)?;
+    }
+    write!(w, "</feed>\n")?;
+    Ok(atom_response(w))
+}
+
+/// Atom feed of the tags, newest first, with their tagger and annotation
+pub async fn tags(Path(repo): Path<String>) -> Result<Response, AppError> {
+    blocking(move || tag_feed(&repo)).await
+}
+
+fn tag_feed(reponame: &str) -> Result<Response, AppError> {
+    let config = Config::load();
+    let repo = open_repo(reponame)?;
+
+    let mut tags = Vec::new();
+    for rf in repo.references()?.filter_map(|rf| rf.ok()) {
+        if !rf.is_tag() {
+            continue;
+        }
+        let Ok(commit) = rf.peel_to_commit() else {
+            continue;
+        };
+        let name = rf.shorthand().unwrap_or("").to_string();
+        let tag = rf.peel_to_tag().ok();
+        tags.push((name, tag, commit));
+    }
+    // Order by the tagger date of annotated tags, the commit date otherwise
+    tags.sort_by_key(|(_, tag, commit)| {
+        std::cmp::Reverse(
+            tag.as_ref()
+                .and_then(|t| t.tagger())
+                .map(|s| s.when())
+                .unwrap_or(commit.time()),
+        )
+    });
+
+    let mut w = String::new();
+    print_feed_header(&mut w, &format!("{}, tags", reponame))?;
+    for (name, tag, commit) in tags.iter().take(config.feed_size) {
+        let ci = get_commitinfo(&repo, commit.id().to_string())?;
+        let tagger = tag.as_ref().and_then(|t| t.tagger());
+        let sig = tagger.as_ref().unwrap_or(&ci.header.author);
+        // Annotated tags have an id of their own, lightweight ones do not
+        let id = tag.as_ref().map(|t| t.id().to_string());
+        write!(w, "<entry>\n")?;
+        write!(w, "<id>{}</id>\n", id.as_ref().unwrap_or(&ci.header.oid))?;
+        print_entry_times(&mut w, sig, sig)?;
+        write!(w, "<title>{}</title>\n", xmlencode(name))?;
+        print_entry_link(&mut w, reponame, &ci.header.oid)?;
+        print_entry_author(&mut w, sig)?;
+        write!(w, "<content>")?;
+        if let Some(msg) = tag.as_ref().and_then(|t| t.message()) {
+            write!(w, "{}\n", xmlencode(msg.trim_end()))?;
+        }
+        print_entry_commit(&mut w, &ci)?;
+        write!(w, "</content>\n")?;
+        write!(w, "</entry>\n")?;
+    }
+    write!(w, "</feed>\n")?;
+    Ok(atom_response(w))
+}
+
+fn atom_response(body: String) -> Response {
+    (
+        [(header::CONTENT_TYPE, "application/atom+xml; charset=utf-8")],
+        body,
+    )
+        .into_response()
+}
+
+fn print_feed_header<W: Write>(w: &mut W, title: &str) -> Result<()> {
+    write!(w, "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n")?;
+    write!(w, "<feed xmlns=\"http://www.w3.org/2005/Atom\">\n")?;
+    write!(w, "<title>{}</title>\n", xmlencode(title))?;
+    Ok(())
+}
+
+fn print_commit_entry<W: Write>(
+    w: &mut W,
+    relpath: &str,
+    ci: &CommitInfo,
+) -> Result<()> {
+    write!(w, "<entry>\n")?;
+    write!(w, "<id>{}</id>\n", ci.header.oid)?;
+    print_entry_times(w, &ci.header.author, &ci.header.committer)?;
+    if let Some(summary) = &ci.header.summary {
+        write!(w, "<title>{}</title>\n", xmlencode(summary))?;
+    }
+    print_entry_link(w, relpath, &ci.header.oid)?;
+    print_entry_author(w, &ci.header.author)?;
+    write!(w, "<content>")?;
+    print_entry_commit(w, ci)?;
+    write!(w, "</content>\n")?;
+    write!(w, "</entry>\n")?;
+    Ok(())
+}
+
+fn print_entry_times<W: Write>(
+    w: &mut W,
+    published: &Signature,
+    updated: &Signature,
+) -> Result<()> {
+    write!(w, "<published>")?;
+    print_time_z(w, published.when())?;
+    write!(w, "</published>\n")?;
+    write!(w, "<updated>")?;
+    print_time_z(w, updated.when())?;
+    write!(w, "</updated>\n")?;
+    Ok(())
+}
+
+fn print_entry_link<W: Write>(
+    w: &mut W,
+    relpath: &str,
+    oid: &str,
+) -> Result<()> {
+    write!(
+        w,
+        "<link rel=\"alternate\" type=\"text/html\" \
+         href=\"/{}/commit/{}\" />\n",
+        relpath, oid
+    )?;
+    Ok(())
+}
+
+fn print_entry_author<W: Write>(w: &mut W, sig: &Signature) -> Result<()> {
+    write!(w, "<author>\n")?;
+    write!(w, "<name>{}</name>\n", xmlencode(sig.name().unwrap_or("")))?;
+    write!(
+        w,
+        "<email>{}</email>\n",
+        xmlencode(sig.email().unwrap_or(""))
+    )?;
+    write!(w, "</author>\n")?;
+    Ok(())
+}
+
+/// Writes the commit header, full message and diffstat as escaped text
+fn print_entry_commit<W: Write>(w: &mut W, ci: &CommitInfo) -> Result<()> {
+    write!(w, "commit {}\n", ci.header.oid)?;
+    if let Some(poid) = &ci.header.parentoid {
+        write!(w, "parent {}\n", poid)?;
+    }
+    write!(
+        w,
+        "Author: {} &lt;{}&gt;\n",
+        xmlencode(ci.header.author.name().unwrap_or("")),
+        xmlencode(ci.header.author.email().unwrap_or(""))
+    )?;
+    write!(w, "Date:   ")?;
+    print_time_z(w, ci.header.author.when())?;
+    write!(w, "\n")?;
+    if let Some(msg) = &ci.header.msg {
+        write!(w, "\n{}\n", xmlencode(msg.trim_end()))?;
+    }
+    write!(w, "\n")?;
+    for (delta, di) in ci.diff.deltas().zip(&ci.deltas) {
+        let c = delta_letter(delta.status());
+        let path = delta
+            .new_file()
+            .path()
+            .or(delta.old_file().path())
+            .unwrap_or(std::path::Path::new(""));
+        write!(
+            w,
+            "{} {} | +{} -{}\n",
+            c,
+            xmlencode(path.display().to_string().as_ref()),
+            di.add_count,
+            di.del_count
+        )?;
+    }
+    print_diffstat_summary(w, ci.file_count, ci.add_count, ci.del_count)?;
+    write!(w, "\n")?;
+    Ok(())
+}
@@ -1,96 +1,323 @@
 use crate::config::Config;
-use crate::data::{self, CommitInfo};
+use crate::data::{
+    self, open_repo, rename_source, resolve_commit, CommitHeader, DiffStat,
+};
 use crate::error::AppError;
-use crate::handlers::header;
-use crate::util::print_time_short;
-use anyhow::{anyhow, Result};
-use axum::{extract::Path, response::Html};
-use git2::{Oid, Repository};
+use crate::extract::{Path, Query};
+use crate::handlers::{footer, header, repo_header};
+use crate::index::Index;
+use crate::state::PooledRepo;
+use crate::util::{print_time_short, urlencode, xmlencode};
+use crate::worker::blocking;
+use anyhow::Result;
+use axum::response::Html;
+use git2::{Commit, Oid, Repository, Revwalk, Tree};
+use serde::Deserialize;
 use std::fmt::Write;
+use std::path::PathBuf;
 
-pub async fn log(Path(repo): Path<String>) -> Result<Html<String>, AppError> {
+#[derive(Deserialize)]
+pub struct LogQuery {
+    offset: Option<usize>,
+    limit: Option<usize>,
+    follow: Option<bool>,
+}
+
+pub async fn log_head(
+    Path(repo): Path<String>,
+    Query(query): Query<LogQuery>,
+) -> Result<Html<String>, AppError> {
+    blocking(move || {
+        render_log(&repo, Config::load().default_rev(&repo), "", &query)
+    })
+    .await
+}
+
+pub async fn log(
+    Path((repo, rev)): Path<(String, String)>,
+    Query(query): Query<LogQuery>,
+) -> Result<Html<String>, AppError> {
+    blocking(move || render_log(&repo, &rev, "", &query)).await
+}
+
+pub async fn log_path(
+    Path((repo, rev, path)): Path<(String, String, String)>,
+    Query(query): Query<LogQuery>,
+) -> Result<Html<String>, AppError> {
+    blocking(move || render_log(&repo, &rev, &path, &query)).await
+}
+
+/// Renders one page of the history reachable from the commit `rev`
+/// resolves to, limited to the commits touching `path` unless it is empty
+fn render_log(
+    reponame: &str,
+    rev: &str,
+    path: &str,
+    query: &LogQuery,
+) -> Result<Html<String>, AppError> {
     let config = Config::load();
     let mut result: Vec<String> = Vec::new();
-    let baseurl = repo.to_string();
+    let repo = open_repo(reponame)?;
+    let commit = resolve_commit(&repo, rev)?;
+    let offset = query.offset.unwrap_or(0);
+    let limit = query
+        .limit
+        .unwrap_or(config.log_page_size)
+        .clamp(1, config.log_max_page_size);
+    let follow = query.follow.unwrap_or(false);
+    let path = path.trim_matches('/');
+
     result.push(header().to_string());
-    result.push(format!("<h1>{repo}</h1>"));
-    result.push(format!("<span>git clone git://{repo}.git</span>"));
-    result.push(format!(
-        "<span>
-    <a href=\"/{baseurl}/log\">Log</a>
-    <a href=\"/{baseurl}/tree\">Tree</a>
-    <a href=\"/{baseurl}/refs\">Refs</a>
-            </span>"
-    ));
-    result.push("<hr/>".to_string());
+    result.push(repo_header(reponame, Some((rev, &commit))));
+    if !path.is_empty() {
+        result.push(format!(
+            "<p>History of <b>{}</b>{}</p>",
+            xmlencode(path),
+            match follow {
+                true => " (following renames)",
+                false => "",
+            }
+        ));
+    }
 
+    let diffstat = config.log_diffstat(reponame);
     result.push("<table id=\"log\">".to_string());
     result.push(
         "<thead><tr>
         <td><b>Date</b></td>
         <td><b>Commit message</b></td>
-        <td><b>Author</b></td>
-        <td><b>Files</b></td>
-        <td align=\"right\"><b>+</b></td>
-        <td align=\"right\"><b>-</b></td>
-        </tr></thread>"
+        <td><b>Author</b></td>"
             .to_string(),
     );
+    if diffstat {
+        result.push(
+            "<td><b>Files</b></td>
+            <td align=\"right\"><b>+</b></td>
+            <td align=\"right\"><b>-</b></td>"
+                .to_string(),
+        );
+    }
+    result.push("</tr></thread>".to_string());
 
-    let repo =
-        Repository::open(std::path::Path::new(&config.dir).join(repo)).unwrap();
+    let ids = history(&repo, reponame, &commit, path, follow)?;
+    let mut buf = String::new();
+    let more =
+        print_log(&mut buf, reponame, &repo, ids, offset, limit, diffstat)?;
+    result.push(buf);
+    result.push("</table>".to_string());
 
-    let oid = repo
-        .head()?
-        .target()
-        .ok_or(anyhow!("No Oid for the current repo HEAD"))?;
+    let mut pageurl = format!("/{}/log/{}", reponame, urlencode(rev, ""));
+    if !path.is_empty() {
+        pageurl.push_str(&format!("/{}", urlencode(path, "/")));
+    }
+    pageurl.push_str(match follow {
+        true => "?follow=true&amp;",
+        false => "?",
+    });
     let mut buf = String::new();
-    print_log(&mut buf, baseurl.as_ref(), &repo, oid)?;
+    print_log_nav(&mut buf, &pageurl, offset, limit, more)?;
     result.push(buf);
+    result.push(footer().to_string());
 
     Ok(Html(result.join("")))
 }
 
+/// Returns the ids of the commits reachable from `commit`, newest first,
+/// limited to the ones touching `path` unless it is empty and following its
+/// renames if `follow` is set. The index of the repository is refreshed on
+/// the way.
+pub fn history<'r>(
+    repo: &'r Repository,
+    reponame: &str,
+    commit: &Commit,
+    path: &str,
+    follow: bool,
+) -> Result<Box<dyn Iterator<Item = Result<Oid>> + 'r>> {
+    let index = Index::open(reponame);
+    if let Some(index) = &index {
+        index.refresh(repo)?;
+    }
+    let mut revwalk = repo.revwalk()?;
+    revwalk.push(commit.id())?;
+    Ok(match path {
+        "" => Box::new(revwalk.map(|id| id.map_err(|e| e.into()))),
+        _ => Box::new(PathHistory {
+            repo,
+            index,
+            revwalk,
+            path: PathBuf::from(path),
+            follow,
+        }),
+    })
+}
+
 fn print_log_line<W: Write>(
     w: &mut W,
     relpath: &str,
-    ci: &CommitInfo,
+    commit: &CommitHeader,
+    stat: Option<&DiffStat>,
 ) -> Result<()> {
     write!(w, "<tr><td>")?;
-    print_time_short(w, ci.author.when())?;
+    print_time_short(w, commit.author.when())?;
     write!(w, "</td><td>")?;
-    if let Some(summary) = &ci.summary {
-        write!(w, "<a href=\"/{}/commit/{}/\">", relpath, ci.oid)?;
-        write!(w, "{}", summary)?;
+    if let Some(summary) = &commit.summary {
+        write!(w, "<a href=\"/{}/commit/{}\">", relpath, commit.oid)?;
+        write!(w, "{}", xmlencode(summary))?;
         write!(w, "</a>")?;
     }
     write!(w, "</td><td>")?;
-    write!(w, "{}", ci.author.name().unwrap_or(""))?;
-    write!(w, "</td><td class=\"num\" align=\"right\">")?;
-    write!(w, "{}", ci.file_count)?;
-    write!(w, "</td><td class=\"num\" align=\"right\">")?;
-    write!(w, "+{}", ci.add_count)?;
-    write!(w, "</td><td class=\"num\" align=\"right\">")?;
-    write!(w, "-{}", ci.del_count)?;
+    write!(w, "{}", xmlencode(commit.author.name().unwrap_or("")))?;
+    if let Some(stat) = stat {
+        write!(w, "</td><td class=\"num\" align=\"right\">")?;
+        write!(w, "{}", stat.files)?;
+        write!(w, "</td><td class=\"num\" align=\"right\">")?;
+        write!(w, "+{}", stat.additions)?;
+        write!(w, "</td><td class=\"num\" align=\"right\">")?;
+        write!(w, "-{}", stat.deletions)?;
+    }
     write!(w, "</td></tr>\n")?;
     Ok(())
 }
 
+/// Writes the log lines for at most `limit` commits out of `ids`, skipping
+/// the first `offset`, along with their diffstat if `diffstat` is set.
+/// Returns whether older commits remain.
 fn print_log<W: Write>(
     w: &mut W,
     relpath: &str,
-    repo: &Repository,
-    oid: Oid,
+    repo: &PooledRepo,
+    ids: impl Iterator<Item = Result<Oid>>,
+    offset: usize,
+    limit: usize,
+    diffstat: bool,
+) -> Result<bool> {
+    let mut ids = ids.skip(offset);
+    for id in ids.by_ref().take(limit) {
+        let commit = repo.find_commit(id?)?;
+        let stat = match diffstat {
+            true => Some(data::get_diffstat(repo, &commit)?),
+            false => None,
+        };
+        let header = CommitHeader::new(&commit);
+        print_log_line(w, relpath, &header, stat.as_ref())?;
+    }
+    Ok(ids.next().is_some())
+}
+
+/// Writes the links to the newer and older pages around the current one,
+/// where `pageurl` is the log URL up to and including the query separator
+fn print_log_nav<W: Write>(
+    w: &mut W,
+    pageurl: &str,
+    offset: usize,
+    limit: usize,
+    more: bool,
 ) -> Result<()> {
-    let mut revwalk = repo.revwalk()?;
-    revwalk.push(oid)?;
-    for id in revwalk {
-        if id.is_err() {
-            break;
-        }
-        let id = id.unwrap();
-        let ci = data::get_commitinfo(repo, id.to_string())?;
-        print_log_line(w, relpath, &ci)?;
+    if offset == 0 && !more {
+        return Ok(());
+    }
+    write!(w, "<p>")?;
+    if offset > 0 {
+        write!(
+            w,
+            "<a href=\"{}offset={}&amp;limit={}\">&larr; Newer</a> ",
+            pageurl,
+            offset.saturating_sub(limit),
+            limit
+        )?;
     }
+    if more {
+        write!(
+            w,
+            "<a href=\"{}offset={}&amp;limit={}\">Older &rarr;</a>",
+            pageurl,
+            offset + limit,
+            limit
+        )?;
+    }
+    write!(w, "</p>")?;
     Ok(())
 }
+
+/// Commits of a revwalk that change `path`, like `git log -- <path>`.
+///
+/// A commit is kept when the entry at `path` differs from every one of its
+/// parents, so merges that take one side unchanged are skipped. With
+/// `follow`, the path is switched to the source of a detected rename once
+/// the commit that introduced it is reached.
+struct PathHistory<'r> {
+    repo: &'r Repository,
+    /// Index telling the paths commits change without looking at their trees
+    index: Option<Index>,
+    revwalk: Revwalk<'r>,
+    path: PathBuf,
+    follow: bool,
+}
+
+impl Iterator for PathHistory<'_> {
+    type Item = Result<Oid>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let id = match self.revwalk.next()? {
+                Ok(id) => id,
+                Err(e) => return Some(Err(e.into())),
+            };
+            match self.touches(id) {
+                Ok(true) => return Some(Ok(id)),
+                Ok(false) => (),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+impl PathHistory<'_> {
+    fn touches(&mut self, id: Oid) -> Result<bool> {
+        let commit = self.repo.find_commit(id)?;
+        // Entries are diffs against the first parent, which only tell the
+        // answer for commits that have no other
+        let entry = match commit.parent_count() {
+            0 | 1 => self.index.as_ref().and_then(|index| index.get(id)),
+            _ => None,
+        };
+        if entry.is_some_and(|entry| !entry.touches(&self.path)) {
+            return Ok(false);
+        }
+        let tree = commit.tree()?;
+        let entry = entry_id(&tree, &self.path);
+        let mut parent_trees = Vec::new();
+        for parent in commit.parents() {
+            let parent_tree = parent.tree()?;
+            if entry_id(&parent_tree, &self.path) == entry {
+                return Ok(false);
+            }
+            parent_trees.push(parent_tree);
+        }
+        if entry.is_none() {
+            // Deleted here, unless this is a root commit lacking the path
+            return Ok(!parent_trees.is_empty());
+        }
+        if self.follow && parent_trees.len() == 1 {
+            let parent_tree = &parent_trees[0];
+            if entry_id(parent_tree, &self.path).is_none() {
+                self.follow_rename(parent_tree, &tree)?;
+            }
+        }
+        Ok(true)
+    }
+
+    /// Switches `path` to where it was renamed from between the two trees
+    fn follow_rename(&mut self, old: &Tree, new: &Tree) -> Result<()> {
+        let source = rename_source(self.repo, old, new, &self.path)?;
+        if let Some(source) = source {
+            self.path = source;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the id of the tree entry at `path`, if any
+fn entry_id(tree: &Tree, path: &std::path::Path) -> Option<Oid> {
+    tree.get_path(path).ok().map(|e| e.id())
+}
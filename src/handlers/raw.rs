@@ -0,0 +1,113 @@
+use crate::data::{find_path, open_repo, resolve_commit};
+use crate::error::{AppError, ClientError};
+use crate::extract::Path;
+use crate::handlers::basename;
+use crate::worker::{blocking, download_turn};
+use axum::{
+    body::{Body, Bytes},
+    http::{header, response::Builder, StatusCode},
+    response::Response,
+};
+use git2::{Oid, Repository};
+use std::io;
+use std::path::PathBuf;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+
+/// Size of the chunks a blob is sent in
+const CHUNK_SIZE: usize = 64 * 1024;
+
+pub async fn raw(
+    Path((repo, rev, path)): Path<(String, String, String)>,
+) -> Result<Response, AppError> {
+    let (response, repo_path, oid) =
+        blocking(move || raw_blob(&repo, &rev, &path)).await?;
+
+    // The blob is handed to the response body chunk by chunk from a blocking
+    // thread, so that it is not copied as a whole on top of the contents
+    // libgit2 holds. It keeps its turn until it is sent.
+    let permit = download_turn().await?;
+    let (tx, rx) = mpsc::channel(16);
+    tokio::task::spawn_blocking(move || {
+        let _permit = permit;
+        if let Err(e) = send_blob(&repo_path, oid, &tx) {
+            let _ = tx.blocking_send(Err(io::Error::other(e.to_string())));
+        }
+    });
+    Ok(response.body(Body::from_stream(ReceiverStream::new(rx)))?)
+}
+
+/// Looks up the blob at `path` and returns the response headers to serve it
+/// with, along with the git directory of the repository and the blob id
+fn raw_blob(
+    reponame: &str,
+    rev: &str,
+    path: &str,
+) -> Result<(Builder, PathBuf, Oid), AppError> {
+    let repo = open_repo(reponame)?;
+    let commit = resolve_commit(&repo, rev)?;
+    let path = path.trim_matches('/');
+    let blob = find_path(&repo, &commit, path)?
+        .into_blob()
+        .map_err(|_| ClientError::PathNotFound(path.to_string()))?;
+
+    let filename = basename(path, '/');
+    let is_binary = blob.is_binary();
+    let disposition = match is_binary {
+        true => "attachment",
+        false => "inline",
+    };
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, content_type(filename, is_binary))
+        .header(header::CONTENT_LENGTH, blob.size())
+        .header(
+            header::CONTENT_DISPOSITION,
+            format!("{}; filename=\"{}\"", disposition, quote(filename)),
+        )
+        .header(header::X_CONTENT_TYPE_OPTIONS, "nosniff");
+    Ok((response, repo.path().to_path_buf(), blob.id()))
+}
+
+/// Sends the contents of the blob `oid` through `tx`, stopping early if the
+/// client went away
+fn send_blob(
+    repo_path: &std::path::Path,
+    oid: Oid,
+    tx: &mpsc::Sender<io::Result<Bytes>>,
+) -> anyhow::Result<()> {
+    let repo = Repository::open(repo_path)?;
+    let blob = repo.find_blob(oid)?;
+    for chunk in blob.content().chunks(CHUNK_SIZE) {
+        if tx.blocking_send(Ok(Bytes::copy_from_slice(chunk))).is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Guesses the Content-Type of a blob from its file extension, falling back
+/// on its contents. Text is always served as `text/plain` so that HTML, SVG
+/// and scripts from a repository never run in the context of ritz.
+fn content_type(filename: &str, is_binary: bool) -> String {
+    let guess = mime_guess::from_path(filename).first();
+    match (guess, is_binary) {
+        (_, false) => "text/plain; charset=utf-8".to_string(),
+        (Some(mime), true) if mime.type_() != mime_guess::mime::TEXT => {
+            mime.essence_str().to_string()
+        }
+        _ => "application/octet-stream".to_string(),
+    }
+}
+
+/// Replaces the characters that cannot appear in a quoted header parameter
+fn quote(filename: &str) -> String {
+    filename
+        .chars()
+        .map(|c| match c {
+            '"' | '\\' => '_',
+            c if c == ' ' || c.is_ascii_graphic() => c,
+            _ => '_',
+        })
+        .collect()
+}
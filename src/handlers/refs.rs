@@ -1,48 +1,48 @@
-use crate::config::Config;
-use crate::data::{get_commitinfo, CommitInfo};
+use crate::data::{open_repo, CommitHeader};
 use crate::error::AppError;
-use crate::handlers::{footer, header};
-use crate::util::{print_time_short, xmlencode};
+use crate::extract::Path;
+use crate::handlers::snapshot::Format;
+use crate::handlers::{footer, header, repo_header};
+use crate::util::{print_time_short, urlencode, xmlencode};
+use crate::worker::blocking;
 use anyhow::Result;
-use axum::{extract::Path, response::Html};
+use axum::response::Html;
 use git2::{Reference, Repository};
 use std::cmp::Ordering;
 use std::fmt::Write;
 
-struct ReferenceInfo<'a> {
-    rf: Reference<'a>,
-    commitinfo: CommitInfo<'a>,
+pub struct ReferenceInfo<'a> {
+    pub rf: Reference<'a>,
+    pub commit: CommitHeader,
 }
 
 pub async fn refs(Path(repo): Path<String>) -> Result<Html<String>, AppError> {
-    let config = Config::load();
+    blocking(move || render_refs(&repo)).await
+}
+
+fn render_refs(reponame: &str) -> Result<Html<String>, AppError> {
     let mut result = String::new();
     result.push_str(&header());
-    result.push_str(&format!("<h1>{repo}</h1>"));
-    result.push_str(&format!("<span>git clone git://{repo}.git</span>"));
-    result.push_str(&format!(
-        "<span>
-    <a href=\"/{repo}/log\">Log</a>
-    <a href=\"/{repo}/tree\">Tree</a>
-    <a href=\"/{repo}/refs\">Refs</a>
-            </span>"
-    ));
-    result.push_str("<hr/>");
+    result.push_str(&repo_header(reponame, None));
 
-    let repo =
-        Repository::open(std::path::Path::new(&config.dir).join(repo)).unwrap();
-    write_refs(&mut result, &repo)?;
+    let baseurl = reponame.to_string();
+    let repo = open_repo(reponame)?;
+    write_refs(&mut result, &baseurl, &repo)?;
     result.push_str(footer());
     Ok(Html(result))
 }
 
-fn write_refs<W: Write>(w: &mut W, repo: &Repository) -> Result<()> {
+fn write_refs<W: Write>(
+    w: &mut W,
+    relpath: &str,
+    repo: &Repository,
+) -> Result<()> {
     let mut j = 0;
     let mut count = 0;
-    let titles = vec!["Branches", "Tags"];
-    let ids = vec!["branches", "tags"];
+    let titles = ["Branches", "Tags"];
+    let ids = ["branches", "tags"];
     let refs = get_refs(repo)?;
-    for (_i, r) in refs.iter().enumerate() {
+    for r in refs.iter() {
         if j == 0 && r.rf.is_tag() {
             if count >= 1 {
                 write!(w, "</tbody></table><br/>\n")?;
@@ -62,22 +62,38 @@ fn write_refs<W: Write>(w: &mut W, repo: &Repository) -> Result<()> {
                    <td><b>Name</b></td>
                    <td><b>Last commit date</b></td>
                    <td><b>Author</b></td>
+                   {}
                    </tr></thead>
                    <tbody>",
-                titles[j], ids[j]
+                titles[j],
+                ids[j],
+                match r.rf.is_tag() {
+                    true => "<td><b>Download</b></td>",
+                    false => "",
+                }
             )?;
         }
 
         write!(w, "<tr><td>")?;
-        write!(w, "{}", xmlencode(&r.rf.shorthand().unwrap_or("")))?;
+        write!(w, "{}", xmlencode(r.rf.shorthand().unwrap_or("")))?;
         write!(w, "</td><td>")?;
-        print_time_short(w, r.commitinfo.author.when())?;
+        print_time_short(w, r.commit.author.when())?;
         write!(w, "</td><td>")?;
-        write!(
-            w,
-            "{}",
-            xmlencode(&r.commitinfo.author.name().unwrap_or(""))
-        )?;
+        write!(w, "{}", xmlencode(r.commit.author.name().unwrap_or("")))?;
+        if r.rf.is_tag() {
+            write!(w, "</td><td>")?;
+            let name = urlencode(r.rf.shorthand().unwrap_or(""), "");
+            for format in Format::ALL {
+                write!(
+                    w,
+                    "<a href=\"/{}/snapshot/{}.{}\">{}</a> ",
+                    relpath,
+                    name,
+                    format.extension(),
+                    format.extension()
+                )?;
+            }
+        }
         write!(w, "</td></tr>\n")?;
     }
     if count >= 1 {
@@ -87,15 +103,14 @@ fn write_refs<W: Write>(w: &mut W, repo: &Repository) -> Result<()> {
 }
 
 /// Returns a [ReferenceInfo] vector of branches and tags sorted by [refs_cmp]
-fn get_refs(repo: &Repository) -> Result<Vec<ReferenceInfo>> {
+pub fn get_refs(repo: &Repository) -> Result<Vec<ReferenceInfo<'_>>> {
     let mut ris = repo
         .references()?
         .filter_map(|rf| rf.ok())
         .filter(|rf| rf.is_tag() | rf.is_branch())
         .filter_map(|rf| {
-            let obj = rf.peel(git2::ObjectType::Any).ok()?;
-            let commitinfo = get_commitinfo(repo, obj.id().to_string()).ok()?;
-            Some(ReferenceInfo { rf, commitinfo })
+            let commit = CommitHeader::new(&rf.peel_to_commit().ok()?);
+            Some(ReferenceInfo { rf, commit })
         })
         .collect::<Vec<ReferenceInfo>>();
     ris.sort_by(refs_cmp);
@@ -107,6 +122,6 @@ fn get_refs(repo: &Repository) -> Result<Vec<ReferenceInfo>> {
 fn refs_cmp(a: &ReferenceInfo, b: &ReferenceInfo) -> Ordering {
     a.rf.is_tag()
         .cmp(&b.rf.is_tag())
-        .then(b.commitinfo.author.when().cmp(&a.commitinfo.author.when()))
+        .then(b.commit.author.when().cmp(&a.commit.author.when()))
         .then(a.rf.shorthand_bytes().cmp(b.rf.shorthand_bytes()))
 }
@@ -0,0 +1,293 @@
+use crate::data::{open_repo, resolve_commit};
+use crate::error::{AppError, ClientError};
+use crate::extract::Path;
+use crate::handlers::basename;
+use crate::worker::{blocking, download_turn};
+use anyhow::Result;
+use axum::{
+    body::{Body, Bytes},
+    http::{header, StatusCode},
+    response::Response,
+};
+use chrono::{DateTime, Datelike, Timelike};
+use flate2::{write::GzEncoder, Compression};
+use git2::{ObjectType, Oid, Repository, Tree, TreeWalkMode, TreeWalkResult};
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use xz2::write::XzEncoder;
+use zip::{write::SimpleFileOptions, CompressionMethod, ZipWriter};
+
+/// Archive formats offered for download, in the order they are listed
+#[derive(Clone, Copy)]
+pub enum Format {
+    TarGz,
+    TarXz,
+    Zip,
+}
+
+impl Format {
+    pub const ALL: [Format; 3] = [Format::TarGz, Format::TarXz, Format::Zip];
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::TarGz => "tar.gz",
+            Format::TarXz => "tar.xz",
+            Format::Zip => "zip",
+        }
+    }
+
+    fn content_type(self) -> &'static str {
+        match self {
+            Format::TarGz => "application/gzip",
+            Format::TarXz => "application/x-xz",
+            Format::Zip => "application/zip",
+        }
+    }
+
+    /// Splits a snapshot file name such as `v1.0.tar.gz` into the revision
+    /// and the archive format
+    pub fn split(file: &str) -> Option<(&str, Format)> {
+        Format::ALL.into_iter().find_map(|f| {
+            let rev = file.strip_suffix(f.extension())?.strip_suffix('.')?;
+            Some((rev, f))
+        })
+    }
+}
+
+pub async fn snapshot(
+    Path((repo, file)): Path<(String, String)>,
+) -> Result<Response, AppError> {
+    let (rev, format) =
+        Format::split(&file).ok_or(ClientError::PathNotFound(file.clone()))?;
+    let (repo_path, oid) = {
+        let (repo, rev) = (repo.clone(), rev.to_string());
+        blocking(move || snapshot_commit(&repo, &rev)).await?
+    };
+
+    // Archive entries live under a `<repo>-<rev>/` directory, as with cgit
+    let name = basename(&repo, '/').trim_end_matches(".git");
+    let prefix = format!("{}-{}", name, rev.replace('/', "-"));
+    let filename = format!("{}.{}", prefix, format.extension());
+
+    // The archive is written on a blocking thread and handed to the response
+    // body chunk by chunk, so it is never held in memory as a whole. It
+    // keeps its turn until it is written.
+    let permit = download_turn().await?;
+    let (tx, rx) = mpsc::channel(16);
+    tokio::task::spawn_blocking(move || {
+        let _permit = permit;
+        let w = ChannelWriter(tx.clone());
+        if let Err(e) = write_snapshot(&repo_path, oid, &prefix, format, w) {
+            let _ = tx.blocking_send(Err(io::Error::other(e.to_string())));
+        }
+    });
+
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, format.content_type())
+        .header(
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{}\"", filename.replace('"', "_")),
+        )
+        .body(Body::from_stream(ReceiverStream::new(rx)))?;
+    Ok(response)
+}
+
+/// Returns the git directory of the repository `reponame` and the commit
+/// `rev` resolves to in it
+fn snapshot_commit(reponame: &str, rev: &str) -> Result<(PathBuf, Oid)> {
+    let repo = open_repo(reponame)?;
+    let oid = resolve_commit(&repo, rev)?.id();
+    Ok((repo.path().to_path_buf(), oid))
+}
+
+/// Forwards everything written to it to the receiving end of the channel
+struct ChannelWriter(mpsc::Sender<io::Result<Bytes>>);
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0
+            .blocking_send(Ok(Bytes::copy_from_slice(buf)))
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A file, directory or symbolic link to put in an archive
+struct ArchiveEntry {
+    path: String,
+    filemode: i32,
+    oid: Oid,
+    kind: Option<ObjectType>,
+}
+
+fn write_snapshot<W: Write>(
+    repo_path: &std::path::Path,
+    oid: Oid,
+    prefix: &str,
+    format: Format,
+    w: W,
+) -> Result<()> {
+    let repo = Repository::open(repo_path)?;
+    let commit = repo.find_commit(oid)?;
+    let mtime = commit.time().seconds();
+    let entries = tree_entries(&commit.tree()?)?;
+    let mut w = BufWriter::with_capacity(64 * 1024, w);
+    match format {
+        Format::TarGz => {
+            let enc = GzEncoder::new(&mut w, Compression::default());
+            let enc = write_tar(&repo, &entries, prefix, mtime, enc)?;
+            enc.finish()?;
+        }
+        Format::TarXz => {
+            let enc = XzEncoder::new(&mut w, 6);
+            let enc = write_tar(&repo, &entries, prefix, mtime, enc)?;
+            enc.finish()?;
+        }
+        Format::Zip => write_zip(&repo, &entries, prefix, mtime, &mut w)?,
+    }
+    w.flush()?;
+    Ok(())
+}
+
+/// Lists every entry of `tree` recursively, parents before their children
+fn tree_entries(tree: &Tree) -> Result<Vec<ArchiveEntry>> {
+    let mut entries = Vec::new();
+    tree.walk(TreeWalkMode::PreOrder, |dir, te| {
+        // Names that are not valid UTF-8 cannot be put in the archives
+        let Some(name) = te.name() else {
+            return TreeWalkResult::Skip;
+        };
+        entries.push(ArchiveEntry {
+            path: format!("{}{}", dir, name),
+            filemode: te.filemode(),
+            oid: te.id(),
+            kind: te.kind(),
+        });
+        TreeWalkResult::Ok
+    })?;
+    Ok(entries)
+}
+
+/// Returns the permission bits to use for a regular file with `filemode`
+fn file_permissions(filemode: i32) -> u32 {
+    match filemode & 0o111 {
+        0 => 0o644,
+        _ => 0o755,
+    }
+}
+
+fn write_tar<W: Write>(
+    repo: &Repository,
+    entries: &[ArchiveEntry],
+    prefix: &str,
+    mtime: i64,
+    w: W,
+) -> Result<W> {
+    let mut builder = tar::Builder::new(w);
+    let new_header = |entry_type, mode| {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(entry_type);
+        header.set_mode(mode);
+        header.set_mtime(mtime.max(0) as u64);
+        header.set_size(0);
+        header
+    };
+
+    let mut header = new_header(tar::EntryType::Directory, 0o755);
+    builder.append_data(&mut header, format!("{}/", prefix), io::empty())?;
+    for entry in entries {
+        let path = format!("{}/{}", prefix, entry.path);
+        match entry.kind {
+            // Submodules are left as empty directories like git-archive does
+            Some(ObjectType::Tree) | Some(ObjectType::Commit) => {
+                let mut header = new_header(tar::EntryType::Directory, 0o755);
+                builder.append_data(
+                    &mut header,
+                    format!("{}/", path),
+                    io::empty(),
+                )?;
+            }
+            Some(ObjectType::Blob) if entry.filemode == 0o120000 => {
+                let blob = repo.find_blob(entry.oid)?;
+                let target = std::str::from_utf8(blob.content())?;
+                let mut header = new_header(tar::EntryType::Symlink, 0o777);
+                builder.append_link(&mut header, path, target)?;
+            }
+            Some(ObjectType::Blob) => {
+                let blob = repo.find_blob(entry.oid)?;
+                let mut header = new_header(
+                    tar::EntryType::Regular,
+                    file_permissions(entry.filemode),
+                );
+                header.set_size(blob.size() as u64);
+                builder.append_data(&mut header, path, blob.content())?;
+            }
+            _ => (),
+        }
+    }
+    Ok(builder.into_inner()?)
+}
+
+fn write_zip<W: Write>(
+    repo: &Repository,
+    entries: &[ArchiveEntry],
+    prefix: &str,
+    mtime: i64,
+    w: W,
+) -> Result<()> {
+    let mut zip = ZipWriter::new_stream(w);
+    let options = SimpleFileOptions::default()
+        .compression_method(CompressionMethod::Deflated)
+        .last_modified_time(zip_time(mtime));
+
+    zip.add_directory(format!("{}/", prefix), options)?;
+    for entry in entries {
+        let path = format!("{}/{}", prefix, entry.path);
+        match entry.kind {
+            Some(ObjectType::Tree) | Some(ObjectType::Commit) => {
+                zip.add_directory(format!("{}/", path), options)?;
+            }
+            Some(ObjectType::Blob) if entry.filemode == 0o120000 => {
+                let blob = repo.find_blob(entry.oid)?;
+                let target = std::str::from_utf8(blob.content())?;
+                zip.add_symlink(path, target, options)?;
+            }
+            Some(ObjectType::Blob) => {
+                let blob = repo.find_blob(entry.oid)?;
+                zip.start_file(
+                    path,
+                    options.unix_permissions(file_permissions(entry.filemode)),
+                )?;
+                zip.write_all(blob.content())?;
+            }
+            _ => (),
+        }
+    }
+    zip.finish()?;
+    Ok(())
+}
+
+/// Converts a UNIX timestamp to the MS-DOS time used in zip archives, which
+/// cannot represent dates before 1980
+fn zip_time(seconds: i64) -> zip::DateTime {
+    DateTime::from_timestamp(seconds, 0)
+        .and_then(|dt| {
+            zip::DateTime::from_date_and_time(
+                dt.year().try_into().ok()?,
+                dt.month() as u8,
+                dt.day() as u8,
+                dt.hour() as u8,
+                dt.minute() as u8,
+                dt.second() as u8,
+            )
+            .ok()
+        })
+        .unwrap_or_default()
+}
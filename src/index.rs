@@ -0,0 +1,213 @@
+use crate::config::Config;
+use crate::data::DiffStat;
+use crate::util::urlencode;
+use anyhow::Result;
+use git2::{Commit, DiffFindOptions, Oid, Repository};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+
+/// Indexes opened so far by repository name, as a database can only be
+/// opened once per process
+static INDEXES: OnceLock<Mutex<HashMap<String, Index>>> = OnceLock::new();
+
+/// What is known about a commit without computing its diff again, compared
+/// to its first parent
+#[derive(Serialize, Deserialize)]
+pub struct IndexEntry {
+    pub files: usize,
+    pub additions: usize,
+    pub deletions: usize,
+    /// Paths of the files added, removed or changed, both old and new for
+    /// renames and copies
+    pub paths: Vec<String>,
+}
+
+impl IndexEntry {
+    /// Returns whether the commit changed `path` or anything below it
+    pub fn touches(&self, path: &Path) -> bool {
+        self.paths.iter().any(|p| Path::new(p).starts_with(path))
+    }
+
+    pub fn stat(&self) -> DiffStat {
+        DiffStat {
+            files: self.files,
+            additions: self.additions,
+            deletions: self.deletions,
+        }
+    }
+}
+
+/// Commit metadata of a repository, stored under `Config.cache_dir` so that
+/// it survives restarts. Every commit reachable from a ref is added in the
+/// background whenever the refs move.
+#[derive(Clone)]
+pub struct Index {
+    /// Entries by commit id
+    commits: sled::Tree,
+    /// Commits the refs pointed to when all of their history was last
+    /// indexed, by ref name
+    tips: sled::Tree,
+    updating: Arc<AtomicBool>,
+}
+
+impl Index {
+    /// Returns the index of the repository `name`, or `None` if
+    /// `Config.cache_dir` is not set or the index cannot be opened
+    pub fn open(name: &str) -> Option<Index> {
+        let dir = Config::load().cache_dir.as_ref()?;
+        let indexes = INDEXES.get_or_init(Default::default);
+        let mut indexes = indexes.lock().unwrap();
+        if let Some(index) = indexes.get(name) {
+            return Some(index.clone());
+        }
+        let path = Path::new(dir).join(urlencode(name, ""));
+        let index = sled::open(&path)
+            .and_then(|db| {
+                Ok(Index {
+                    commits: db.open_tree("commits")?,
+                    tips: db.open_tree("tips")?,
+                    updating: Arc::new(AtomicBool::new(false)),
+                })
+            })
+            .map_err(|e| eprintln!("ritz: {}: {}", path.display(), e))
+            .ok()?;
+        indexes.insert(name.to_string(), index.clone());
+        Some(index)
+    }
+
+    pub fn get(&self, oid: Oid) -> Option<IndexEntry> {
+        let value = self.commits.get(oid.as_bytes()).ok()??;
+        serde_json::from_slice(&value).ok()
+    }
+
+    fn insert(&self, oid: Oid, entry: &IndexEntry) -> Result<()> {
+        self.commits
+            .insert(oid.as_bytes(), serde_json::to_vec(entry)?)?;
+        Ok(())
+    }
+
+    /// Returns the entry of `commit`, computing and storing it if the index
+    /// lacks it
+    pub fn entry(
+        &self,
+        repo: &Repository,
+        commit: &Commit,
+    ) -> Result<IndexEntry> {
+        if let Some(entry) = self.get(commit.id()) {
+            return Ok(entry);
+        }
+        let entry = compute_entry(repo, commit)?;
+        self.insert(commit.id(), &entry)?;
+        Ok(entry)
+    }
+
+    /// Starts indexing, on a thread of its own, the commits that became
+    /// reachable since the last update if the refs of `repo` moved, unless
+    /// an update is already running
+    pub fn refresh(&self, repo: &Repository) -> Result<()> {
+        let tips = ref_tips(repo)?;
+        let old_tips = self.old_tips();
+        if tips == old_tips || self.updating.swap(true, Ordering::AcqRel) {
+            return Ok(());
+        }
+        let index = self.clone();
+        let path = repo.path().to_path_buf();
+        std::thread::spawn(move || {
+            if let Err(e) = Repository::open(&path)
+                .map_err(|e| e.into())
+                .and_then(|repo| index.update(&repo, &tips, &old_tips))
+            {
+                eprintln!("ritz: indexing {}: {:#}", path.display(), e);
+            }
+            index.updating.store(false, Ordering::Release);
+        });
+        Ok(())
+    }
+
+    /// Returns the tips recorded by the last update
+    fn old_tips(&self) -> HashMap<String, Oid> {
+        self.tips
+            .iter()
+            .filter_map(|kv| kv.ok())
+            .filter_map(|(k, v)| {
+                let name = String::from_utf8(k.to_vec()).ok()?;
+                Some((name, Oid::from_bytes(&v).ok()?))
+            })
+            .collect()
+    }
+
+    /// Adds the commits reachable from `tips` but not from `old_tips`, then
+    /// records `tips`
+    fn update(
+        &self,
+        repo: &Repository,
+        tips: &HashMap<String, Oid>,
+        old_tips: &HashMap<String, Oid>,
+    ) -> Result<()> {
+        let mut revwalk = repo.revwalk()?;
+        for oid in tips.values() {
+            revwalk.push(*oid)?;
+        }
+        for oid in old_tips.values() {
+            // Commits that no longer exist have nothing left to hide
+            let _ = revwalk.hide(*oid);
+        }
+        for id in revwalk {
+            let commit = repo.find_commit(id?)?;
+            self.entry(repo, &commit)?;
+        }
+
+        self.tips.clear()?;
+        for (name, oid) in tips {
+            self.tips.insert(name.as_bytes(), oid.as_bytes())?;
+        }
+        self.tips.flush()?;
+        Ok(())
+    }
+}
+
+/// Returns the commits the refs of `repo` point to, by ref name
+fn ref_tips(repo: &Repository) -> Result<HashMap<String, Oid>> {
+    let mut tips = HashMap::new();
+    for rf in repo.references()?.filter_map(|rf| rf.ok()) {
+        let (Some(name), Ok(commit)) = (rf.name(), rf.peel_to_commit()) else {
+            continue;
+        };
+        tips.insert(name.to_string(), commit.id());
+    }
+    Ok(tips)
+}
+
+/// Diffs `commit` against its first parent, counting files the same way as
+/// the commit page
+pub fn compute_entry(repo: &Repository, commit: &Commit) -> Result<IndexEntry> {
+    let parent_tree = commit.parent(0).ok().and_then(|c| c.tree().ok());
+    let mut diff = repo.diff_tree_to_tree(
+        parent_tree.as_ref(),
+        Some(&commit.tree()?),
+        None,
+    )?;
+    let files = diff.deltas().len();
+    let mut opts = DiffFindOptions::new();
+    opts.renames(true).copies(true).exact_match_only(true);
+    diff.find_similar(Some(&mut opts))?;
+    let stats = diff.stats()?;
+    let mut paths = Vec::new();
+    for delta in diff.deltas() {
+        let old = delta.old_file().path().and_then(|p| p.to_str());
+        let new = delta.new_file().path().and_then(|p| p.to_str());
+        paths.extend(old.map(|p| p.to_string()));
+        if new != old {
+            paths.extend(new.map(|p| p.to_string()));
+        }
+    }
+    Ok(IndexEntry {
+        files,
+        additions: stats.insertions(),
+        deletions: stats.deletions(),
+        paths,
+    })
+}
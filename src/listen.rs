@@ -0,0 +1,129 @@
+use crate::config::{Config, ListenAddr};
+use crate::discover::route_nested_repo;
+use anyhow::{Context, Result};
+use axum::{Router, ServiceExt as _};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto,
+    service::TowerToHyperService,
+};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::io::{FromRawFd, IntoRawFd, RawFd};
+use tokio::net::{TcpListener, UnixListener};
+use tower::ServiceExt;
+
+/// First file descriptor passed by systemd socket activation
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Variables of systemd socket activation, which children such as
+/// git-upload-pack must not inherit lest they believe they were activated
+pub const ACTIVATION_VARS: [&str; 3] =
+    ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"];
+
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+/// Returns the sockets inherited through `LISTEN_FDS` if ritz was started by
+/// systemd socket activation, and otherwise binds the configured addresses
+pub fn bind(config: &Config) -> Result<Vec<Listener>> {
+    if let Some(listeners) = inherited()? {
+        return Ok(listeners);
+    }
+    config
+        .listen
+        .iter()
+        .map(|addr| bind_addr(addr, config.socket_mode))
+        .collect()
+}
+
+fn bind_addr(addr: &ListenAddr, mode: u32) -> Result<Listener> {
+    let listener = match addr {
+        ListenAddr::Tcp(sock_addr) => {
+            let listener = std::net::TcpListener::bind(sock_addr)
+                .with_context(|| format!("Cannot listen on {}", addr))?;
+            listener.set_nonblocking(true)?;
+            Listener::Tcp(TcpListener::from_std(listener)?)
+        }
+        ListenAddr::Unix(path) => {
+            // A socket left behind by an earlier run would make bind fail
+            if std::fs::symlink_metadata(path)
+                .is_ok_and(|m| m.file_type().is_socket())
+            {
+                std::fs::remove_file(path)?;
+            }
+            let listener = UnixListener::bind(path)
+                .with_context(|| format!("Cannot listen on {}", addr))?;
+            std::fs::set_permissions(path, PermissionsExt::from_mode(mode))?;
+            Listener::Unix(listener)
+        }
+    };
+    Ok(listener)
+}
+
+/// Takes over the sockets passed as described in sd_listen_fds(3), if they
+/// are meant for this process
+fn inherited() -> Result<Option<Vec<Listener>>> {
+    let pid = std::env::var("LISTEN_PID").ok();
+    let fds = std::env::var("LISTEN_FDS").ok();
+    let (Some(pid), Some(fds)) = (pid, fds) else {
+        return Ok(None);
+    };
+    if pid.parse::<u32>().ok() != Some(std::process::id()) {
+        return Ok(None);
+    }
+    // The variables are left as they are, since changing the environment is
+    // unsound once the runtime has started threads; children are run without
+    // them instead, see [ACTIVATION_VARS]
+    let fds = fds.parse::<RawFd>().context("LISTEN_FDS: invalid value")?;
+
+    let mut listeners = Vec::new();
+    for fd in LISTEN_FDS_START..LISTEN_FDS_START + fds {
+        // SAFETY: systemd hands these descriptors over to this process, and
+        // nothing else in it uses them
+        let unix = unsafe { std::os::unix::net::UnixListener::from_raw_fd(fd) };
+        // getsockname tells a Unix domain socket apart from a TCP one
+        let listener = match unix.local_addr() {
+            Ok(_) => {
+                unix.set_nonblocking(true)?;
+                Listener::Unix(UnixListener::from_std(unix)?)
+            }
+            Err(_) => {
+                // SAFETY: the descriptor was just released by `unix`
+                let tcp = unsafe {
+                    std::net::TcpListener::from_raw_fd(unix.into_raw_fd())
+                };
+                tcp.set_nonblocking(true)?;
+                Listener::Tcp(TcpListener::from_std(tcp)?)
+            }
+        };
+        listeners.push(listener);
+    }
+    Ok(Some(listeners))
+}
+
+/// Serves `app` on `listener` until accepting connections fails
+pub async fn serve(listener: Listener, app: Router) -> Result<()> {
+    match listener {
+        Listener::Tcp(listener) => {
+            let service = app.map_request(route_nested_repo);
+            axum::serve(listener, service.into_make_service()).await?
+        }
+        Listener::Unix(listener) => loop {
+            let (stream, _) = listener.accept().await?;
+            let service = TowerToHyperService::new(
+                app.clone().map_request(route_nested_repo),
+            );
+            tokio::spawn(async move {
+                let _ = auto::Builder::new(TokioExecutor::new())
+                    .serve_connection_with_upgrades(
+                        TokioIo::new(stream),
+                        service,
+                    )
+                    .await;
+            });
+        },
+    }
+    Ok(())
+}
@@ -1,26 +1,175 @@
+#![allow(clippy::write_with_newline)]
+
+mod check;
+mod conditional;
 mod config;
 mod data;
+mod discover;
 mod error;
+mod extract;
 mod handlers;
+mod index;
+mod listen;
+mod render;
+mod state;
 mod util;
-use axum::{routing::get, Router};
-use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+mod worker;
+use anyhow::{bail, Result};
+use axum::{
+    extract::DefaultBodyLimit,
+    middleware,
+    routing::{get, post},
+    Router,
+};
+use clap::{Args, Parser, Subcommand};
+use config::{Config, ListenAddr, Overrides};
+use std::net::IpAddr;
+use std::path::PathBuf;
+
+/// Largest fetch negotiation request accepted from git clients
+const UPLOAD_PACK_BODY_LIMIT: usize = 64 * 1024 * 1024;
+
+/// A minimal, fast, and dynamic web interface for git
+#[derive(Parser)]
+#[command(version)]
+struct Cli {
+    /// Configuration file to read instead of $RITZ_CONFIG
+    #[arg(long, global = true)]
+    config: Option<String>,
+    /// Directory containing the repositories
+    #[arg(long, global = true)]
+    dir: Option<String>,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Serve the repositories over HTTP (the default)
+    Serve(ServeArgs),
+    /// Validate the configuration and list the repositories found
+    Check,
+    /// Write the pages of every repository to a directory
+    Render {
+        /// Directory to write the pages to
+        #[arg(short, long, default_value = "public")]
+        output: PathBuf,
+    },
+}
+
+#[derive(Args, Default)]
+struct ServeArgs {
+    /// Address to listen on
+    #[arg(long)]
+    bind: Option<IpAddr>,
+    /// Port to listen on
+    #[arg(short, long)]
+    port: Option<u16>,
+    /// Address to listen on, as <ip>:<port> or unix:<path>, instead of the
+    /// bind address and port; may be repeated
+    #[arg(long)]
+    listen: Vec<ListenAddr>,
+    /// Permissions of the Unix domain sockets, in octal
+    #[arg(long, value_parser = config::parse_mode)]
+    socket_mode: Option<u32>,
+}
 
 #[tokio::main]
 async fn main() {
-    let conf = config::Config::load();
-    let app = Router::new()
-        .route("/", get(handlers::root))
-        .route("/static/*path", get(handlers::asset::asset))
-        .route("/:repo", get(handlers::log::log))
+    if let Err(e) = run(Cli::parse()).await {
+        eprintln!("ritz: {:#}", e);
+        std::process::exit(1);
+    }
+}
+
+async fn run(cli: Cli) -> Result<()> {
+    let command = cli.command.unwrap_or(Command::Serve(ServeArgs::default()));
+    let mut overrides = Overrides {
+        dir: cli.dir,
+        ..Default::default()
+    };
+    if let Command::Serve(args) = &command {
+        overrides.bind = args.bind;
+        overrides.port = args.port;
+        overrides.listen = args.listen.clone();
+        overrides.socket_mode = args.socket_mode;
+    }
+    let conf = Config::init(cli.config.as_deref(), overrides)?;
+    match command {
+        Command::Serve(_) => {
+            let app = app();
+            let mut servers = tokio::task::JoinSet::new();
+            for listener in listen::bind(conf)? {
+                servers.spawn(listen::serve(listener, app.clone()));
+            }
+            // Stop as soon as any of the listeners fails
+            if let Some(result) = servers.join_next().await {
+                result??;
+            }
+        }
+        Command::Check => {
+            let problems = check::check(conf)?;
+            if problems > 0 {
+                bail!("{} problem(s) found", problems);
+            }
+        }
+        Command::Render { output } => render::render(app(), &output).await?,
+    }
+    Ok(())
+}
+
+/// Returns the router serving every page of the site
+fn app() -> Router {
+    let api = Router::new()
+        .route("/stats", get(handlers::api::stats))
+        .route("/repos", get(handlers::api::repos))
+        .route("/repos/:repo/commit/:hash", get(handlers::api::commit))
+        .route("/repos/:repo/log", get(handlers::api::log_head))
+        .route("/repos/:repo/log/:rev", get(handlers::api::log))
+        .route("/repos/:repo/log/:rev/*path", get(handlers::api::log_path))
+        .route("/repos/:repo/refs", get(handlers::api::refs))
+        .route("/repos/:repo/tree/:rev", get(handlers::api::tree_root))
+        .route("/repos/:repo/tree/:rev/*path", get(handlers::api::tree))
+        .route_layer(middleware::from_fn(conditional::conditional))
+        .fallback(handlers::api::not_found);
+    // Pages made from the contents of repositories, which can be cached
+    let browse = Router::new()
+        .route("/:repo", get(handlers::log::log_head))
+        .route("/:repo/atom.xml", get(handlers::feed::atom))
+        .route("/:repo/blame/:rev/*path", get(handlers::blame::blame))
         .route("/:repo/commit/:hash", get(handlers::commit::commit))
-        .route("/:repo/log", get(handlers::log::log))
+        .route("/:repo/log", get(handlers::log::log_head))
+        .route("/:repo/log/:rev", get(handlers::log::log))
+        .route("/:repo/log/:rev/*path", get(handlers::log::log_path))
+        .route("/:repo/raw/:rev/*path", get(handlers::raw::raw))
         .route("/:repo/refs", get(handlers::refs::refs))
-        .route("/:repo/tree/*path", get(handlers::tree));
-    let sock_addr =
-        SocketAddr::from((IpAddr::V6(Ipv6Addr::LOCALHOST), conf.port));
-    axum::Server::bind(&sock_addr)
-        .serve(app.into_make_service())
-        .await
-        .unwrap();
+        .route("/:repo/snapshot/:file", get(handlers::snapshot::snapshot))
+        .route("/:repo/tags.xml", get(handlers::feed::tags))
+        .route("/:repo/tree", get(handlers::tree_head))
+        .route("/:repo/tree/:rev", get(handlers::tree_root))
+        .route("/:repo/tree/:rev/*path", get(handlers::tree))
+        .route_layer(middleware::from_fn(conditional::conditional));
+    Router::new()
+        .route("/", get(handlers::root))
+        .nest("/api/v1", api)
+        .route("/static/*path", get(handlers::asset::asset))
+        .merge(browse)
+        .route("/:repo/HEAD", get(handlers::clone::head))
+        .route("/:repo/info/refs", get(handlers::clone::info_refs))
+        .route(
+            "/:repo/git-upload-pack",
+            post(handlers::clone::git_upload_pack)
+                .layer(DefaultBodyLimit::max(UPLOAD_PACK_BODY_LIMIT)),
+        )
+        .route(
+            "/:repo/objects/info/packs",
+            get(handlers::clone::info_packs),
+        )
+        .route("/:repo/objects/pack/:file", get(handlers::clone::pack_file))
+        .route(
+            "/:repo/objects/:dir/:file",
+            get(handlers::clone::loose_object),
+        )
+        .route_layer(middleware::from_fn(discover::require_exported))
+        .fallback(handlers::not_found)
 }
@@ -0,0 +1,304 @@
+use crate::config::Config;
+use crate::data::open_repo;
+use crate::discover::route_nested_repo;
+use crate::handlers::list_repos;
+use crate::util::{urldecode, urlencode};
+use anyhow::{bail, Result};
+use axum::{
+    body::{to_bytes, Body},
+    http::{header, Request, StatusCode},
+    Router,
+};
+use git2::{ObjectType, TreeWalkMode, TreeWalkResult};
+use std::collections::HashMap;
+use std::path::Path;
+use tower::ServiceExt;
+
+/// A page of the static site
+struct Page {
+    /// URL the page is served at by `ritz serve`, query included
+    url: String,
+    /// File the page is written to, relative to the output directory
+    file: String,
+    /// Whether an existing file is already up to date, as is the case for
+    /// pages named after an object id
+    immutable: bool,
+}
+
+/// Pages to write, along with the file every URL linking to one of them
+/// leads to once written, keyed by the percent-decoded URL path, query
+/// included for the pages of the log
+#[derive(Default)]
+struct Site {
+    pages: Vec<Page>,
+    files: HashMap<String, String>,
+}
+
+impl Site {
+    fn add(&mut self, url: String, file: String, immutable: bool) {
+        self.alias(&url, &file);
+        self.pages.push(Page {
+            url,
+            file,
+            immutable,
+        });
+    }
+
+    /// Makes links to `url` lead to the page written to `file`
+    fn alias(&mut self, url: &str, file: &str) {
+        self.files.insert(url_key(url), file.to_string());
+    }
+
+    /// Returns the file the link `url` leads to, looked up with its query
+    /// first and then without it
+    fn file(&self, url: &str) -> Option<&String> {
+        let path = url.split('?').next()?;
+        self.files
+            .get(&url_key(url))
+            .or_else(|| self.files.get(&url_key(path)))
+    }
+}
+
+/// Writes the index and, for every repository, the log and the refs,
+/// tree and commit pages of its default branch plus its feeds to `output`.
+/// Links between the pages are made relative so that the result can be put
+/// on any static file server, and links to pages that only `ritz serve` can
+/// answer, such as blame or snapshots, are dropped. Commit pages already in
+/// `output` are left as they are.
+pub async fn render(app: Router, output: &Path) -> Result<()> {
+    let config = Config::load();
+    let mut site = Site::default();
+    site.add("/".to_string(), "index.html".to_string(), false);
+    site.add(
+        "/static/style.css".to_string(),
+        "static/style.css".to_string(),
+        false,
+    );
+    for repo in list_repos(&config.dir) {
+        if let Err(e) = add_repo(&mut site, config, &repo) {
+            eprintln!("ritz: skipping {}: {:#}", repo, e);
+        }
+    }
+
+    let mut failed = 0;
+    for page in &site.pages {
+        let path = output.join(&page.file);
+        if page.immutable && path.exists() {
+            continue;
+        }
+        match render_page(&app, &site, page, &path).await {
+            Ok(()) => println!("{}", path.display()),
+            Err(e) => {
+                eprintln!("ritz: {}: {:#}", page.url, e);
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        bail!("{} page(s) could not be written", failed);
+    }
+    Ok(())
+}
+
+async fn render_page(
+    app: &Router,
+    site: &Site,
+    page: &Page,
+    path: &Path,
+) -> Result<()> {
+    let request = Request::get(&page.url).body(Body::empty())?;
+    let response = app
+        .clone()
+        .map_request(route_nested_repo)
+        .oneshot(request)
+        .await?;
+    if response.status() != StatusCode::OK {
+        bail!("{}", response.status());
+    }
+    let is_markup = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| {
+            v.starts_with("text/html") || v.starts_with("application/atom+xml")
+        });
+    let body = to_bytes(response.into_body(), usize::MAX).await?;
+    let body = match is_markup {
+        true => relative_links(site, page, &String::from_utf8(body.into())?)
+            .into_bytes(),
+        false => body.into(),
+    };
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(path, body)?;
+    Ok(())
+}
+
+/// Adds the pages of the repository `reponame` to `site`
+fn add_repo(site: &mut Site, config: &Config, reponame: &str) -> Result<()> {
+    let repo = open_repo(reponame)?;
+    let rev = config.default_rev(reponame);
+    let revpath = urlencode(rev, "");
+    let commit = repo.revparse_single(rev)?.peel_to_commit()?;
+
+    let mut revwalk = repo.revwalk()?;
+    revwalk.push(commit.id())?;
+    let oids = revwalk.collect::<Result<Vec<_>, _>>()?;
+
+    // The log is written a page of `Config.log_page_size` commits at a
+    // time, the newest one being the page of the repository
+    let log = format!("{}/index.html", reponame);
+    let logurl = format!("/{}/log/{}", reponame, revpath);
+    let size = config.log_page_size.max(1);
+    site.add(logurl.clone(), log.clone(), false);
+    site.alias(&format!("/{}", reponame), &log);
+    site.alias(&format!("/{}/log", reponame), &log);
+    site.alias(&format!("{}?offset=0&limit={}", logurl, size), &log);
+    for page in 1..oids.len().div_ceil(size) {
+        site.add(
+            format!("{}?offset={}&limit={}", logurl, page * size, size),
+            format!("{}/log/{}.html", reponame, page),
+            false,
+        );
+    }
+    site.add(
+        format!("/{}/refs", reponame),
+        format!("{}/refs.html", reponame),
+        false,
+    );
+    for feed in ["atom.xml", "tags.xml"] {
+        site.add(
+            format!("/{}/{}", reponame, feed),
+            format!("{}/{}", reponame, feed),
+            false,
+        );
+    }
+
+    let tree = format!("{}/tree.html", reponame);
+    site.add(
+        format!("/{}/tree/{}", reponame, revpath),
+        tree.clone(),
+        false,
+    );
+    site.alias(&format!("/{}/tree", reponame), &tree);
+    let mut entries = Vec::new();
+    commit.tree()?.walk(TreeWalkMode::PreOrder, |dir, te| {
+        let Some(name) = te.name() else {
+            return TreeWalkResult::Skip;
+        };
+        // Such names would let the pages escape the output directory
+        if name == "." || name == ".." {
+            return TreeWalkResult::Skip;
+        }
+        entries.push((format!("{}{}", dir, name), te.kind()));
+        TreeWalkResult::Ok
+    })?;
+    for (path, kind) in entries {
+        let pathurl = urlencode(&path, "/");
+        match kind {
+            Some(ObjectType::Tree) => (),
+            Some(ObjectType::Blob) => site.add(
+                format!("/{}/raw/{}/{}", reponame, revpath, pathurl),
+                format!("{}/raw/{}", reponame, path),
+                false,
+            ),
+            // Submodules have no page of their own
+            _ => continue,
+        }
+        site.add(
+            format!("/{}/tree/{}/{}", reponame, revpath, pathurl),
+            format!("{}/tree/{}.html", reponame, path),
+            false,
+        );
+    }
+
+    for oid in oids {
+        site.add(
+            format!("/{}/commit/{}", reponame, oid),
+            format!("{}/commit/{}.html", reponame, oid),
+            true,
+        );
+    }
+    Ok(())
+}
+
+/// Rewrites the `href` attributes in `markup` to point at the files of the
+/// pages they link to, relative to the file of `page`, and removes the ones
+/// linking to pages that are not part of `site`
+fn relative_links(site: &Site, page: &Page, markup: &str) -> String {
+    const HREF: &str = "href=\"";
+    let mut result = String::with_capacity(markup.len());
+    let mut rest = markup;
+    while let Some(start) = rest.find(HREF) {
+        let value_start = start + HREF.len();
+        let Some(len) = rest[value_start..].find('"') else {
+            break;
+        };
+        let value = &rest[value_start..value_start + len];
+        result.push_str(&rest[..start]);
+        if let Some(href) = relative_link(site, page, value) {
+            result.push_str(HREF);
+            result.push_str(&href);
+            result.push('"');
+        } else if result.ends_with(' ') {
+            result.pop();
+        }
+        rest = &rest[value_start + len + 1..];
+    }
+    result.push_str(rest);
+    result
+}
+
+/// Returns what to replace the link `href` found in `page` with, if anything
+fn relative_link(site: &Site, page: &Page, href: &str) -> Option<String> {
+    // Fragments and other schemes such as mailto: are left alone
+    if href.starts_with('#') || href.split('/').next()?.contains(':') {
+        return Some(href.to_string());
+    }
+    let (href, fragment) = match href.split_once('#') {
+        Some((href, fragment)) => (href, format!("#{}", fragment)),
+        None => (href, String::new()),
+    };
+    let (path, query) = match href.split_once('?') {
+        Some((path, query)) => (path, format!("?{}", query)),
+        None => (href, String::new()),
+    };
+    let page_url = page.url.split('?').next()?;
+    let target = site.file(&format!("{}{}", resolve(page_url, path), query))?;
+
+    // Climb out of the directory of the page, then down to the target
+    let from = page.file.split('/').collect::<Vec<_>>();
+    let to = target.split('/').collect::<Vec<_>>();
+    let from_dir = &from[..from.len() - 1];
+    let common = from_dir.iter().zip(&to).take_while(|(a, b)| a == b).count();
+    let mut parts = vec![".."; from_dir.len() - common];
+    parts.extend(&to[common..]);
+    Some(format!("{}{}", urlencode(&parts.join("/"), "/"), fragment))
+}
+
+/// Returns the key of `url` in [Site], as found in `href` attributes or not
+fn url_key(url: &str) -> String {
+    urldecode(&url.replace("&amp;", "&"))
+}
+
+/// Resolves the URL path `href` against the URL path of the page it is on
+fn resolve(base: &str, href: &str) -> String {
+    if href.starts_with('/') {
+        return href.to_string();
+    }
+    let mut parts = base.split('/').collect::<Vec<_>>();
+    parts.pop();
+    for part in href.split('/') {
+        match part {
+            "." => (),
+            ".." => {
+                if parts.len() > 1 {
+                    parts.pop();
+                }
+            }
+            _ => parts.push(part),
+        }
+    }
+    parts.join("/")
+}
@@ -0,0 +1,174 @@
+use crate::config::Config;
+use crate::data::DiffStat;
+use git2::{Oid, Repository};
+use lru::LruCache;
+use serde::Serialize;
+use std::num::NonZeroUsize;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+
+static STATE: OnceLock<AppState> = OnceLock::new();
+
+/// State shared by all requests: repositories opened earlier, kept around to
+/// be used again, and the diffstats computed so far
+pub struct AppState {
+    /// Idle repositories along with their name, least recently used first
+    pool: Mutex<Vec<(String, Repository)>>,
+    pool_size: usize,
+    /// Diffstats by commit id, which never go stale since a commit cannot
+    /// change; `None` if `Config.diffstat_cache_size` is 0
+    diffstats: Option<Mutex<LruCache<Oid, DiffStat>>>,
+    repo_hits: AtomicU64,
+    repo_misses: AtomicU64,
+    diffstat_hits: AtomicU64,
+    diffstat_misses: AtomicU64,
+}
+
+/// Counters of [AppState], as served by the API
+#[derive(Serialize)]
+pub struct Stats {
+    repos: CacheStats,
+    diffstats: CacheStats,
+}
+
+#[derive(Serialize)]
+pub struct CacheStats {
+    hits: u64,
+    misses: u64,
+    len: usize,
+    capacity: usize,
+}
+
+impl AppState {
+    /// Returns the state, creating it with the limits of the configuration
+    /// the first time
+    pub fn load() -> &'static Self {
+        STATE.get_or_init(|| {
+            let config = Config::load();
+            AppState {
+                pool: Mutex::new(Vec::new()),
+                pool_size: config.repo_pool_size,
+                diffstats: NonZeroUsize::new(config.diffstat_cache_size)
+                    .map(|size| Mutex::new(LruCache::new(size))),
+                repo_hits: AtomicU64::new(0),
+                repo_misses: AtomicU64::new(0),
+                diffstat_hits: AtomicU64::new(0),
+                diffstat_misses: AtomicU64::new(0),
+            }
+        })
+    }
+
+    /// Returns the repository `name` from the pool, or else the one `open`
+    /// returns. It goes back to the pool once dropped.
+    pub fn repo<E>(
+        &'static self,
+        name: &str,
+        open: impl FnOnce() -> Result<Repository, E>,
+    ) -> Result<PooledRepo, E> {
+        let pooled = {
+            let mut pool = self.pool.lock().unwrap();
+            pool.iter()
+                .rposition(|(n, _)| n == name)
+                .map(|i| pool.remove(i).1)
+        };
+        let repo = match pooled {
+            Some(repo) => {
+                self.repo_hits.fetch_add(1, Ordering::Relaxed);
+                repo
+            }
+            None => {
+                self.repo_misses.fetch_add(1, Ordering::Relaxed);
+                open()?
+            }
+        };
+        Ok(PooledRepo {
+            state: self,
+            name: name.to_string(),
+            repo: Some(repo),
+        })
+    }
+
+    fn put_back(&self, name: String, repo: Repository) {
+        if self.pool_size == 0 {
+            return;
+        }
+        let mut pool = self.pool.lock().unwrap();
+        if pool.len() >= self.pool_size {
+            pool.remove(0);
+        }
+        pool.push((name, repo));
+    }
+
+    /// Returns the cached diffstat of the commit `oid`, or else computes it
+    /// with `compute` and caches it
+    pub fn diffstat<E>(
+        &self,
+        oid: Oid,
+        compute: impl FnOnce() -> Result<DiffStat, E>,
+    ) -> Result<DiffStat, E> {
+        let Some(cache) = &self.diffstats else {
+            self.diffstat_misses.fetch_add(1, Ordering::Relaxed);
+            return compute();
+        };
+        if let Some(stat) = cache.lock().unwrap().get(&oid) {
+            self.diffstat_hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(*stat);
+        }
+        // The lock is not held while computing, so that requests for other
+        // commits are not held up
+        self.diffstat_misses.fetch_add(1, Ordering::Relaxed);
+        let stat = compute()?;
+        cache.lock().unwrap().put(oid, stat);
+        Ok(stat)
+    }
+
+    pub fn stats(&self) -> Stats {
+        let diffstats = self.diffstats.as_ref().map(|c| c.lock().unwrap());
+        Stats {
+            repos: CacheStats {
+                hits: self.repo_hits.load(Ordering::Relaxed),
+                misses: self.repo_misses.load(Ordering::Relaxed),
+                len: self.pool.lock().unwrap().len(),
+                capacity: self.pool_size,
+            },
+            diffstats: CacheStats {
+                hits: self.diffstat_hits.load(Ordering::Relaxed),
+                misses: self.diffstat_misses.load(Ordering::Relaxed),
+                len: diffstats.as_ref().map_or(0, |c| c.len()),
+                capacity: diffstats.as_ref().map_or(0, |c| c.cap().get()),
+            },
+        }
+    }
+}
+
+/// A repository taken from the pool of [AppState], to which it returns when
+/// dropped
+pub struct PooledRepo {
+    state: &'static AppState,
+    name: String,
+    repo: Option<Repository>,
+}
+
+impl PooledRepo {
+    /// Returns the name the repository was opened with
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl Deref for PooledRepo {
+    type Target = Repository;
+
+    fn deref(&self) -> &Repository {
+        self.repo.as_ref().expect("repository already returned")
+    }
+}
+
+impl Drop for PooledRepo {
+    fn drop(&mut self) {
+        if let Some(repo) = self.repo.take() {
+            self.state.put_back(std::mem::take(&mut self.name), repo);
+        }
+    }
+}
@@ -1,19 +1,18 @@
 use anyhow::{anyhow, Result};
-use chrono::{DateTime, FixedOffset, NaiveDateTime};
-use git2::Time;
+use chrono::{DateTime, FixedOffset};
+use git2::{Delta, Time};
 use std::fmt::Write;
 
 pub fn print_time<W: Write>(w: &mut W, intime: Time) -> Result<()> {
-    let utc = NaiveDateTime::from_timestamp_opt(intime.seconds(), 0)
+    let utc = DateTime::from_timestamp(intime.seconds(), 0)
         .ok_or(anyhow!("Error parsing timestamp seconds: {:#?}", intime))?;
     let offset = FixedOffset::east_opt(intime.offset_minutes() * 60).ok_or(
         anyhow!("Error parsing timestamp offset minutes: {:#?}", intime),
     )?;
-    let dt: DateTime<FixedOffset> =
-        DateTime::from_naive_utc_and_offset(utc, offset);
+    let dt: DateTime<FixedOffset> = utc.with_timezone(&offset);
     let fmt_dt = dt.format("%a, %Y %b %e %H:%M:%S %:z");
     write!(w, "{}", fmt_dt)?;
-    return Ok(());
+    Ok(())
 }
 
 pub fn print_time_short<W: Write>(w: &mut W, intime: Time) -> Result<()> {
@@ -21,7 +20,63 @@ pub fn print_time_short<W: Write>(w: &mut W, intime: Time) -> Result<()> {
         .ok_or(anyhow!("Error parsing timestamp seconds: {:#?}", intime))?;
     let fmt_dt = dt.format("%Y-%m-%d %H:%M");
     write!(w, "{}", fmt_dt)?;
-    return Ok(());
+    Ok(())
+}
+
+/// Print the time in UTC in the RFC 3339 format used by Atom feeds
+pub fn print_time_z<W: Write>(w: &mut W, intime: Time) -> Result<()> {
+    let dt = DateTime::from_timestamp(intime.seconds(), 0)
+        .ok_or(anyhow!("Error parsing timestamp seconds: {:#?}", intime))?;
+    let fmt_dt = dt.format("%Y-%m-%dT%H:%M:%SZ");
+    write!(w, "{}", fmt_dt)?;
+    Ok(())
+}
+
+/// Print the time in the format of HTTP dates, as in `Last-Modified`
+pub fn print_time_http<W: Write>(w: &mut W, intime: Time) -> Result<()> {
+    let dt = DateTime::from_timestamp(intime.seconds(), 0)
+        .ok_or(anyhow!("Error parsing timestamp seconds: {:#?}", intime))?;
+    let fmt_dt = dt.format("%a, %d %b %Y %H:%M:%S GMT");
+    write!(w, "{}", fmt_dt)?;
+    Ok(())
+}
+
+/// Print the summary of a diffstat, as in `2 files changed, 3 insertions(+),
+/// 1 deletion(-)`
+pub fn print_diffstat_summary<W: Write>(
+    w: &mut W,
+    files: usize,
+    additions: usize,
+    deletions: usize,
+) -> Result<()> {
+    let plural = |n: usize| match n {
+        1 => "",
+        _ => "s",
+    };
+    write!(
+        w,
+        "{} file{} changed, {} insertion{}(+), {} deletion{}(-)",
+        files,
+        plural(files),
+        additions,
+        plural(additions),
+        deletions,
+        plural(deletions),
+    )?;
+    Ok(())
+}
+
+/// Returns the letter `git diff --name-status` shows for `status`, or a space
+pub fn delta_letter(status: Delta) -> char {
+    match status {
+        Delta::Added => 'A',
+        Delta::Copied => 'C',
+        Delta::Deleted => 'D',
+        Delta::Modified => 'M',
+        Delta::Renamed => 'R',
+        Delta::Typechange => 'T',
+        _ => ' ',
+    }
 }
 
 /// Escape characters below as HTML 2.0 / XML 1.0
@@ -29,7 +84,7 @@ pub fn xmlencode(input: &str) -> String {
     let mut result = String::new();
     for c in input.chars() {
         match c {
-            '<' => result.push_str("&lt"),
+            '<' => result.push_str("&lt;"),
             '>' => result.push_str("&gt;"),
             '\'' => result.push_str("&#39;"),
             '&' => result.push_str("&amp;"),
@@ -45,7 +100,7 @@ pub fn xmlencodeline(input: &str) -> String {
     let mut result = String::new();
     for c in input.chars() {
         match c {
-            '<' => result.push_str("&lt"),
+            '<' => result.push_str("&lt;"),
             '>' => result.push_str("&gt;"),
             '\'' => result.push_str("&#39;"),
             '&' => result.push_str("&amp;"),
@@ -56,3 +111,48 @@ pub fn xmlencodeline(input: &str) -> String {
     }
     result
 }
+
+/// Percent-encode everything but RFC 3986 unreserved characters and the
+/// characters in `safe`, for use in URL paths
+pub fn urlencode(input: &str, safe: &str) -> String {
+    let mut result = String::new();
+    for b in input.bytes() {
+        match b {
+            b'A'..=b'Z'
+            | b'a'..=b'z'
+            | b'0'..=b'9'
+            | b'-'
+            | b'.'
+            | b'_'
+            | b'~' => result.push(b as char),
+            _ if b.is_ascii() && safe.contains(b as char) => {
+                result.push(b as char)
+            }
+            _ => result.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    result
+}
+
+/// Decode the percent-encoded bytes in a URL path, replacing invalid UTF-8
+pub fn urldecode(input: &str) -> String {
+    let bytes = input.as_bytes();
+    let mut result = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3).and_then(|h| {
+            u8::from_str_radix(std::str::from_utf8(h).ok()?, 16).ok()
+        });
+        match (bytes[i], hex) {
+            (b'%', Some(b)) => {
+                result.push(b);
+                i += 3;
+            }
+            (b, _) => {
+                result.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&result).into_owned()
+}
@@ -0,0 +1,67 @@
+use crate::config::Config;
+use crate::error::Timeout;
+use anyhow::Result;
+use std::sync::OnceLock;
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+/// Turns to work on repositories, `Config.git_workers` of them
+static WORKERS: OnceLock<Semaphore> = OnceLock::new();
+
+/// Turns to stream downloads, `Config.git_workers` of them, apart from
+/// [WORKERS] as they last for as long as clients take to download them
+static DOWNLOADS: OnceLock<Semaphore> = OnceLock::new();
+
+/// Waits for a turn to work on repositories, which lasts until the permit is
+/// dropped
+async fn turn() -> Result<SemaphorePermit<'static>> {
+    let config = Config::load();
+    let workers = WORKERS.get_or_init(|| Semaphore::new(config.git_workers));
+    Ok(workers.acquire().await?)
+}
+
+/// Waits for a turn to stream a snapshot archive or a raw blob, which lasts
+/// until the permit is dropped, or returns [Timeout] if none came within
+/// `Config.request_timeout`
+pub async fn download_turn() -> Result<SemaphorePermit<'static>> {
+    let config = Config::load();
+    let downloads =
+        DOWNLOADS.get_or_init(|| Semaphore::new(config.git_workers));
+    match tokio::time::timeout(config.request_timeout, downloads.acquire())
+        .await
+    {
+        Ok(permit) => Ok(permit?),
+        Err(_) => Err(Timeout.into()),
+    }
+}
+
+/// Runs `f`, which works on repositories through libgit2 and may block for a
+/// long time, on a thread of its own so that the runtime keeps serving other
+/// requests. At most `Config.git_workers` of them run at once. If `f` has not
+/// finished within `Config.request_timeout`, waiting for its turn included,
+/// [Timeout] is returned instead; as libgit2 cannot be interrupted, `f` then
+/// still runs to completion and keeps its turn until it does.
+///
+/// Snapshots and raw blobs take a [download_turn] instead, as they are
+/// streamed for as long as the client takes to download them. Streaming is
+/// therefore not subject to the timeout, and slow clients must not hold up
+/// other requests.
+pub async fn blocking<T, E, F>(f: F) -> Result<T, E>
+where
+    F: FnOnce() -> Result<T, E> + Send + 'static,
+    T: Send + 'static,
+    E: From<anyhow::Error> + Send + 'static,
+{
+    let work = async {
+        let permit = turn().await?;
+        tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            f()
+        })
+        .await
+        .map_err(|e| E::from(anyhow::Error::from(e)))?
+    };
+    match tokio::time::timeout(Config::load().request_timeout, work).await {
+        Ok(result) => result,
+        Err(_) => Err(E::from(Timeout.into())),
+    }
+}